@@ -1,11 +1,83 @@
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    domain::{
+        event::{DisconnectReason, JoinRejection},
+        history::HistoryEntry,
+        message::MessagePayload,
+        room::RoomState,
+    },
+    websocket::ConnectionInfo,
+};
+
 #[derive(Deserialize)]
 pub struct CreateRoomRequest {
     #[serde(rename = "type")]
     pub room_type: String,
     #[serde(rename = "hostId")]
     pub host_id: String,
+    /// Further users who may connect as hosts
+    #[serde(rename = "coHostIds", default)]
+    pub co_host_ids: Vec<String>,
+    #[serde(rename = "maxUsers")]
+    pub max_users: Option<usize>,
+    /// Queue joiners beyond `maxUsers` instead of rejecting them
+    #[serde(default)]
+    pub waitlist: bool,
+    #[serde(rename = "allowedUsers")]
+    pub allowed_users: Option<Vec<String>>,
+    #[serde(rename = "joinSecret")]
+    pub join_secret: Option<String>,
+    /// Keep recent broadcasts for late joiners
+    pub history: Option<HistoryRequest>,
+}
+
+/// At least one limit is required; the count defaults to the server maximum
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryRequest {
+    pub max_messages: Option<usize>,
+    pub max_age_secs: Option<u64>,
+}
+
+/// Fields left out stay as they are
+#[derive(Deserialize)]
+pub struct UpdateRoomRequest {
+    #[serde(rename = "hostId")]
+    pub host_id: Option<String>,
+    /// Replaces the co-hosts; removed ones are disconnected
+    #[serde(rename = "coHostIds")]
+    pub co_host_ids: Option<Vec<String>>,
+}
+
+/// Replaces both access settings; `null` clears a setting
+#[derive(Deserialize)]
+pub struct UpdateRoomAccessRequest {
+    #[serde(rename = "allowedUsers")]
+    pub allowed_users: Option<Vec<String>>,
+    #[serde(rename = "joinSecret")]
+    pub join_secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BanUserRequest {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    /// Permanent ban when absent
+    #[serde(rename = "durationSecs")]
+    pub duration_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct KickUserParams {
+    /// `Kicked` when absent
+    pub reason: Option<DisconnectReason>,
+}
+
+#[derive(Deserialize)]
+pub struct SystemMessageRequest {
+    #[serde(default)]
+    pub message: MessagePayload,
 }
 
 #[derive(Serialize)]
@@ -19,9 +91,63 @@ pub struct CreateRoomResponse {
 pub struct RoomWithPlayerCount {
     pub room_id: String,
     pub host_id: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub co_host_ids: Vec<String>,
     #[serde(rename = "type")]
     pub room_type: String,
+    pub state: RoomState,
+    /// Members and waitlisted users
     pub player_count: usize,
+    pub waitlist_count: usize,
+    /// Spectators are not counted in `player_count`
+    pub spectator_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_users: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_users: Option<Vec<String>>,
+    pub has_join_secret: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomDetailsResponse {
+    #[serde(flatten)]
+    pub room: RoomWithPlayerCount,
+    pub host_status: HostStatus,
+    /// Host sockets open on this instance, oldest first
+    pub host_connections: Vec<HostConnection>,
+}
+
+/// Status of the room's hosts taken together
+#[derive(Serialize)]
+pub enum HostStatus {
+    /// At least one host is connected
+    Connected,
+    /// Every host dropped, some within the reconnect grace period
+    Away,
+    Disconnected,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostConnection {
+    pub user_id: String,
+    #[serde(flatten)]
+    pub connection: ConnectionInfo,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomUser {
+    pub user_id: String,
+    pub joined_at: u64,
+    /// Absent unless the user's socket is open on this instance
+    pub connection: Option<ConnectionInfo>,
+}
+
+#[derive(Serialize)]
+pub struct RoomUsersResponse {
+    pub users: Vec<RoomUser>,
 }
 
 #[derive(Serialize)]
@@ -33,6 +159,15 @@ pub struct RoomsPageResponse {
     pub size: usize,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessagesPageResponse {
+    pub messages: Vec<HistoryEntry>,
+    pub total_messages: usize,
+    pub page: usize,
+    pub size: usize,
+}
+
 #[derive(Deserialize)]
 pub struct PaginationParams {
     pub page: Option<usize>,
@@ -45,4 +180,20 @@ pub struct WsQueryParams {
     pub room_id: String,
     #[serde(rename = "type")]
     pub connection_type: String,
+    #[serde(rename = "resumeToken")]
+    pub resume_token: Option<String>,
+    #[serde(rename = "joinSecret")]
+    pub join_secret: Option<String>,
+    /// Keep sent messages until the client acknowledges them
+    #[serde(default)]
+    pub ack: bool,
+    /// Protocol version the client speaks; the current one when absent
+    #[serde(rename = "protocolVersion")]
+    pub protocol_version: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct JoinRejectedResponse {
+    pub error: &'static str,
+    pub reason: JoinRejection,
 }
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
 use axum::{
     Extension, Json,
@@ -11,15 +11,21 @@ use axum_keycloak_auth::{decode::KeycloakToken, expect_role};
 use crate::{
     AppState,
     api::dto::{
-        CreateRoomRequest, CreateRoomResponse, PaginationParams, RoomWithPlayerCount,
-        RoomsPageResponse,
+        BanUserRequest, CreateRoomRequest, CreateRoomResponse, HostConnection, HostStatus,
+        KickUserParams, MessagesPageResponse, PaginationParams, RoomDetailsResponse, RoomUser,
+        RoomUsersResponse, RoomWithPlayerCount, RoomsPageResponse, SystemMessageRequest,
+        UpdateRoomAccessRequest, UpdateRoomRequest,
     },
     auth::Role,
     domain::{
         event::DisconnectReason,
+        history::HistoryLimits,
+        message::{ToHostMessage, ToUserMessage},
         room::{Room, RoomType},
         user::UserId,
     },
+    storage::{CreateRoomError, HostPresence},
+    websocket,
 };
 
 pub async fn create_room(
@@ -29,7 +35,45 @@ pub async fn create_room(
 ) -> impl IntoResponse {
     expect_role!(&token, Role::Admin);
 
-    let room = Room::new(UserId::new(&body.host_id), RoomType::new(&body.room_type));
+    if body.max_users == Some(0) {
+        return (StatusCode::BAD_REQUEST, "maxUsers must be positive").into_response();
+    }
+
+    let max_messages = state.config.history_max_messages;
+    let history = match body.history {
+        Some(history) if history.max_messages.is_none() && history.max_age_secs.is_none() => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "history needs maxMessages or maxAgeSecs",
+            )
+                .into_response();
+        }
+        Some(history)
+            if history
+                .max_messages
+                .is_some_and(|n| n == 0 || n > max_messages) =>
+        {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("history.maxMessages must be between 1 and {max_messages}"),
+            )
+                .into_response();
+        }
+        Some(history) => Some(HistoryLimits {
+            max_messages: history.max_messages.unwrap_or(max_messages),
+            max_age_secs: history.max_age_secs,
+        }),
+        None => None,
+    };
+
+    let allowed_users = body
+        .allowed_users
+        .map(|users| users.iter().map(UserId::new).collect());
+    let room = Room::new(UserId::new(&body.host_id), RoomType::new(&body.room_type))
+        .with_co_hosts(body.co_host_ids.iter().map(UserId::new).collect())
+        .with_capacity(body.max_users, body.waitlist)
+        .with_access(allowed_users, body.join_secret)
+        .with_history(history);
 
     match state.storage.create_room(room) {
         Ok(room_id) => {
@@ -48,7 +92,7 @@ pub async fn create_room(
             )
                 .into_response()
         }
-        Err(_) => {
+        Err(CreateRoomError::RoomAlreadyExists) => {
             tracing::error!(
                 "Failed to create room for host {} and type {}: room already exists",
                 body.host_id,
@@ -56,6 +100,15 @@ pub async fn create_room(
             );
             (StatusCode::CONFLICT, "Room already exists").into_response()
         }
+        Err(CreateRoomError::Backend(e)) => {
+            tracing::error!(
+                "Failed to create room for host {} and type {}: {}",
+                body.host_id,
+                body.room_type,
+                e,
+            );
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create room").into_response()
+        }
     }
 }
 
@@ -77,15 +130,26 @@ pub async fn cancel_room(
     // Get all users before removing the room
     let users = state.storage.clear_room_users(&room_id);
 
-    // Disconnect all users
+    // Disconnect all users and spectators
     state
         .message_bus
-        .disconnect_room_users(&room_id, &users, DisconnectReason::RoomClosed);
-
-    // Disconnect host
+        .disconnect_room_users(&room_id, &users, DisconnectReason::RoomClosed)
+        .await;
     state
         .message_bus
-        .disconnect_host(&room_id, &room.host_id, DisconnectReason::RoomClosed);
+        .send_to_spectators(
+            &room_id,
+            ToUserMessage::disconnect(room.host_id.clone(), DisconnectReason::RoomClosed),
+        )
+        .await;
+
+    // Disconnect hosts
+    for host_id in room.host_ids() {
+        state
+            .message_bus
+            .disconnect_host(&room_id, host_id, DisconnectReason::RoomClosed)
+            .await;
+    }
 
     // Remove room
     state.storage.remove_room(&room_id);
@@ -94,6 +158,385 @@ pub async fn cancel_room(
     StatusCode::NO_CONTENT.into_response()
 }
 
+pub async fn get_room(
+    Extension(token): Extension<KeycloakToken<Role>>,
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+) -> impl IntoResponse {
+    expect_role!(&token, Role::Admin);
+
+    let Some(room) = state.storage.get_room(&room_id) else {
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    };
+
+    let host_connections: Vec<HostConnection> = state
+        .connections
+        .hosts(&room_id)
+        .into_iter()
+        .map(|(user_id, connection)| HostConnection {
+            user_id: user_id.as_str().to_string(),
+            connection,
+        })
+        .collect();
+    // Hosts on other instances count too
+    let presence = state.storage.get_host_presence(&room_id);
+    let host_status = if presence.iter().any(|(_, p)| *p == HostPresence::Connected) {
+        HostStatus::Connected
+    } else if !presence.is_empty() {
+        HostStatus::Away
+    } else {
+        HostStatus::Disconnected
+    };
+
+    Json(RoomDetailsResponse {
+        room: room_summary(&state, room),
+        host_status,
+        host_connections,
+    })
+    .into_response()
+}
+
+/// Hand the room to another host and/or replace its co-hosts
+pub async fn update_room(
+    Extension(token): Extension<KeycloakToken<Role>>,
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    Json(body): Json<UpdateRoomRequest>,
+) -> impl IntoResponse {
+    expect_role!(&token, Role::Admin);
+
+    if body.host_id.is_none() && body.co_host_ids.is_none() {
+        return (StatusCode::BAD_REQUEST, "Nothing to update").into_response();
+    }
+    if state.storage.get_room(&room_id).is_none() {
+        tracing::warn!("Attempted to update non-existent room {}", room_id);
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    }
+
+    if let Some(host_id) = &body.host_id {
+        let new_host_id = UserId::new(host_id);
+        // A member known to lack the Host role could never host the room
+        if state
+            .storage
+            .get_room_member(&room_id, &new_host_id)
+            .is_some_and(|member| !member.can_host)
+        {
+            return (StatusCode::CONFLICT, "User lacks the Host role").into_response();
+        }
+
+        let Some(previous) = websocket::transfer_host(&state, &room_id, &new_host_id).await else {
+            return (StatusCode::NOT_FOUND, "Room not found").into_response();
+        };
+        tracing::info!(
+            "Room {} host changed from {} to {} by user {}",
+            room_id,
+            previous.as_str(),
+            host_id,
+            token.subject
+        );
+    }
+
+    if let Some(co_host_ids) = body.co_host_ids {
+        let co_host_ids: HashSet<UserId> = co_host_ids.iter().map(UserId::new).collect();
+        let mut removed = Vec::new();
+        state.storage.update_room(&room_id, &mut |room| {
+            let previous = std::mem::take(&mut room.co_host_ids);
+            room.set_co_hosts(co_host_ids.clone());
+            removed = previous
+                .into_iter()
+                .filter(|user_id| !room.is_host(user_id))
+                .collect();
+        });
+        for user_id in &removed {
+            websocket::dismiss_host(&state, &room_id, user_id, DisconnectReason::Kicked).await;
+        }
+        tracing::info!(
+            "Room {} co-hosts set to {:?} by user {}",
+            room_id,
+            co_host_ids,
+            token.subject
+        );
+    }
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Members in the order they joined
+pub async fn list_room_users(
+    Extension(token): Extension<KeycloakToken<Role>>,
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+) -> impl IntoResponse {
+    expect_role!(&token, Role::Admin);
+
+    if state.storage.get_room(&room_id).is_none() {
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    }
+
+    let mut members = state.storage.get_room_members(&room_id);
+    members.sort_by_key(|member| member.joined_at);
+    let users = members
+        .into_iter()
+        .map(|member| RoomUser {
+            connection: state.connections.user(&room_id, &member.user_id),
+            user_id: member.user_id.as_str().to_string(),
+            joined_at: member.joined_at,
+        })
+        .collect();
+
+    Json(RoomUsersResponse { users }).into_response()
+}
+
+pub async fn kick_user(
+    Extension(token): Extension<KeycloakToken<Role>>,
+    State(state): State<Arc<AppState>>,
+    Path((room_id, user_id)): Path<(String, String)>,
+    Query(params): Query<KickUserParams>,
+) -> impl IntoResponse {
+    expect_role!(&token, Role::Admin);
+
+    let reason = params.reason.unwrap_or(DisconnectReason::Kicked);
+    // The socket would take it for a reconnect and keep the membership
+    if reason == DisconnectReason::NewConnection {
+        return (
+            StatusCode::BAD_REQUEST,
+            "NewConnection is not a kick reason",
+        )
+            .into_response();
+    }
+
+    let user_id = UserId::new(&user_id);
+    if state.storage.get_room(&room_id).is_none() {
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    }
+    // Waitlisted users can be kicked too
+    if !websocket::kick_user(&state, &room_id, &user_id, reason.clone()).await {
+        return (StatusCode::NOT_FOUND, "User is not in the room").into_response();
+    }
+
+    tracing::info!(
+        "User {} kicked from room {} with reason {:?} by user {}",
+        user_id.as_str(),
+        room_id,
+        reason,
+        token.subject
+    );
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Send a `System` event to one member
+pub async fn message_user(
+    Extension(token): Extension<KeycloakToken<Role>>,
+    State(state): State<Arc<AppState>>,
+    Path((room_id, user_id)): Path<(String, String)>,
+    Json(body): Json<SystemMessageRequest>,
+) -> impl IntoResponse {
+    expect_role!(&token, Role::Admin);
+
+    let user_id = UserId::new(&user_id);
+    if let Err(error) = check_member(&state, &room_id, &user_id) {
+        return (StatusCode::NOT_FOUND, error).into_response();
+    }
+
+    state
+        .message_bus
+        .send_to_user(
+            &user_id,
+            &room_id,
+            ToUserMessage::system(user_id.clone(), body.message),
+        )
+        .await;
+
+    tracing::info!(
+        "User {} in room {} sent a system message by user {}",
+        user_id.as_str(),
+        room_id,
+        token.subject
+    );
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Send a `System` event to every member and the host
+pub async fn broadcast_system_message(
+    Extension(token): Extension<KeycloakToken<Role>>,
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    Json(body): Json<SystemMessageRequest>,
+) -> impl IntoResponse {
+    expect_role!(&token, Role::Admin);
+
+    let Some(room) = state.storage.get_room(&room_id) else {
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    };
+
+    for user_id in state.storage.get_room_users(&room_id) {
+        state
+            .message_bus
+            .send_to_user(
+                &user_id,
+                &room_id,
+                ToUserMessage::system(user_id.clone(), body.message.clone()),
+            )
+            .await;
+    }
+    state
+        .message_bus
+        .send_to_host(&room_id, ToHostMessage::system(room.host_id, body.message))
+        .await;
+
+    tracing::info!(
+        "System message broadcast to room {} by user {}",
+        room_id,
+        token.subject
+    );
+    StatusCode::NO_CONTENT.into_response()
+}
+
+pub async fn update_room_access(
+    Extension(token): Extension<KeycloakToken<Role>>,
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    Json(body): Json<UpdateRoomAccessRequest>,
+) -> impl IntoResponse {
+    expect_role!(&token, Role::Admin);
+
+    let allowed_users: Option<HashSet<UserId>> = body
+        .allowed_users
+        .map(|users| users.iter().map(UserId::new).collect());
+    let updated = state.storage.update_room(&room_id, &mut |room| {
+        room.allowed_users = allowed_users.clone();
+        room.join_secret = body.join_secret.clone();
+    });
+
+    if updated.is_none() {
+        tracing::warn!(
+            "Attempted to update access of non-existent room {}",
+            room_id
+        );
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    }
+
+    tracing::info!("Room {} access updated by user {}", room_id, token.subject);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+pub async fn get_room_state(
+    Extension(token): Extension<KeycloakToken<Role>>,
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+) -> impl IntoResponse {
+    expect_role!(&token, Role::Admin);
+
+    match state.storage.get_room(&room_id) {
+        Some(room) => Json(room.shared_state).into_response(),
+        None => (StatusCode::NOT_FOUND, "Room not found").into_response(),
+    }
+}
+
+/// Recorded broadcasts, newest first
+pub async fn get_room_messages(
+    Extension(token): Extension<KeycloakToken<Role>>,
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    Query(params): Query<PaginationParams>,
+) -> impl IntoResponse {
+    expect_role!(&token, Role::Admin);
+
+    let page = params.page.unwrap_or(0);
+    let size = params.size.unwrap_or(10);
+
+    if size == 0 || size > 100 {
+        return (StatusCode::BAD_REQUEST, "Invalid pagination parameters").into_response();
+    }
+
+    if state.storage.get_room(&room_id).is_none() {
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    }
+    let Some(history) = state.storage.get_history(&room_id, None) else {
+        return (StatusCode::NOT_FOUND, "Room does not keep history").into_response();
+    };
+
+    let total = history.len();
+    let messages = history
+        .into_iter()
+        .rev()
+        .skip(page.saturating_mul(size))
+        .take(size)
+        .collect();
+
+    Json(MessagesPageResponse {
+        messages,
+        total_messages: total,
+        page,
+        size,
+    })
+    .into_response()
+}
+
+pub async fn ban_user(
+    Extension(token): Extension<KeycloakToken<Role>>,
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    Json(body): Json<BanUserRequest>,
+) -> impl IntoResponse {
+    expect_role!(&token, Role::Admin);
+
+    let user_id = UserId::new(&body.user_id);
+    let duration = body.duration_secs.map(Duration::from_secs);
+    let updated = state.storage.update_room(&room_id, &mut |room| {
+        room.ban(user_id.clone(), duration);
+    });
+
+    if updated.is_none() {
+        tracing::warn!("Attempted to ban user in non-existent room {}", room_id);
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    }
+
+    websocket::kick_user(&state, &room_id, &user_id, DisconnectReason::Banned).await;
+    state
+        .message_bus
+        .disconnect_spectator(&room_id, &user_id, DisconnectReason::Banned)
+        .await;
+
+    tracing::info!(
+        "User {} banned from room {} for {:?} by user {}",
+        body.user_id,
+        room_id,
+        duration,
+        token.subject
+    );
+    StatusCode::NO_CONTENT.into_response()
+}
+
+pub async fn unban_user(
+    Extension(token): Extension<KeycloakToken<Role>>,
+    State(state): State<Arc<AppState>>,
+    Path((room_id, user_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    expect_role!(&token, Role::Admin);
+
+    let mut was_banned = false;
+    let updated = state.storage.update_room(&room_id, &mut |room| {
+        was_banned = room.unban(&UserId::new(&user_id));
+    });
+
+    if updated.is_none() {
+        tracing::warn!("Attempted to unban user in non-existent room {}", room_id);
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    }
+    if !was_banned {
+        return (StatusCode::NOT_FOUND, "User is not banned").into_response();
+    }
+
+    tracing::info!(
+        "User {} unbanned from room {} by user {}",
+        user_id,
+        room_id,
+        token.subject
+    );
+    StatusCode::NO_CONTENT.into_response()
+}
+
 pub async fn list_rooms(
     Extension(token): Extension<KeycloakToken<Role>>,
     State(state): State<Arc<AppState>>,
@@ -111,16 +554,7 @@ pub async fn list_rooms(
     let (rooms, total) = state.storage.get_rooms_paginated(page, size);
     let rooms: Vec<RoomWithPlayerCount> = rooms
         .into_iter()
-        .map(|room| {
-            let room_id_str = room.id.to_string();
-            let player_count = state.storage.get_room_user_count(&room_id_str);
-            RoomWithPlayerCount {
-                room_id: room_id_str,
-                host_id: room.host_id.as_str().to_string(),
-                room_type: room.room_type.as_str().to_string(),
-                player_count,
-            }
-        })
+        .map(|room| room_summary(&state, room))
         .collect();
 
     tracing::info!(
@@ -138,3 +572,47 @@ pub async fn list_rooms(
     })
     .into_response()
 }
+
+/// Why the user cannot be addressed, if the room or membership is missing
+fn check_member(state: &AppState, room_id: &str, user_id: &UserId) -> Result<(), &'static str> {
+    if state.storage.get_room(room_id).is_none() {
+        return Err("Room not found");
+    }
+    if !state.storage.is_user_in_room(room_id, user_id) {
+        return Err("User is not in the room");
+    }
+    Ok(())
+}
+
+fn room_summary(state: &AppState, room: Room) -> RoomWithPlayerCount {
+    let room_id_str = room.id.to_string();
+    let waitlist_count = state.storage.get_waitlist_count(&room_id_str);
+    let player_count = state.storage.get_room_user_count(&room_id_str) + waitlist_count;
+    let spectator_count = state.storage.get_spectator_count(&room_id_str);
+    RoomWithPlayerCount {
+        room_id: room_id_str,
+        host_id: room.host_id.as_str().to_string(),
+        co_host_ids: {
+            let mut co_host_ids: Vec<String> = room
+                .co_host_ids
+                .iter()
+                .map(|user_id| user_id.as_str().to_string())
+                .collect();
+            co_host_ids.sort();
+            co_host_ids
+        },
+        room_type: room.room_type.as_str().to_string(),
+        state: room.state,
+        player_count,
+        waitlist_count,
+        spectator_count,
+        max_users: room.max_users,
+        allowed_users: room.allowed_users.map(|users| {
+            users
+                .into_iter()
+                .map(|user| user.as_str().to_string())
+                .collect()
+        }),
+        has_join_secret: room.join_secret.is_some(),
+    }
+}
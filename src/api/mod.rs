@@ -2,13 +2,37 @@ pub mod dto;
 pub mod handlers;
 pub mod routes;
 
-use axum::{Json, http::StatusCode, response::IntoResponse};
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::State,
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+};
 use serde_json::json;
 
+use crate::{AppState, domain::room::constant_time_eq, metrics::METRICS};
+
 pub async fn ping() -> Json<serde_json::Value> {
     Json(json!({"ping": "pong!"}))
 }
 
+pub async fn metrics(State(state): State<Arc<AppState>>, headers: HeaderMap) -> impl IntoResponse {
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .zip(state.config.metrics_token.as_ref())
+        .is_some_and(|(token, expected)| constant_time_eq(token.as_bytes(), expected.as_bytes()));
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let body = METRICS.render(state.storage.count_rooms_by_type());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
 pub async fn not_found() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"})))
 }
@@ -28,7 +28,41 @@ pub fn room_routes() -> Router<Arc<AppState>> {
         )
         .route(
             "/api/rooms/{roomId}",
-            routing::delete(handlers::cancel_room),
+            routing::get(handlers::get_room)
+                .patch(handlers::update_room)
+                .delete(handlers::cancel_room),
+        )
+        .route(
+            "/api/rooms/{roomId}/users",
+            routing::get(handlers::list_room_users),
+        )
+        .route(
+            "/api/rooms/{roomId}/users/{userId}",
+            routing::delete(handlers::kick_user),
+        )
+        .route(
+            "/api/rooms/{roomId}/users/{userId}/messages",
+            routing::post(handlers::message_user),
+        )
+        .route(
+            "/api/rooms/{roomId}/access",
+            routing::put(handlers::update_room_access),
+        )
+        .route(
+            "/api/rooms/{roomId}/state",
+            routing::get(handlers::get_room_state),
+        )
+        .route(
+            "/api/rooms/{roomId}/messages",
+            routing::get(handlers::get_room_messages).post(handlers::broadcast_system_message),
+        )
+        .route(
+            "/api/rooms/{roomId}/bans",
+            routing::post(handlers::ban_user),
+        )
+        .route(
+            "/api/rooms/{roomId}/bans/{userId}",
+            routing::delete(handlers::unban_user),
         )
         .layer(keycloak_layer)
 }
@@ -11,6 +11,7 @@ pub enum Role {
     Admin,
     Host,
     User,
+    Spectator,
     Unknown(String),
 }
 
@@ -20,6 +21,7 @@ static KEYCLOAK: OnceLock<Arc<KeycloakAuthInstance>> = OnceLock::new();
 const ROLE_ADMIN: &str = "reactive-rooms:scope:write";
 const ROLE_HOST: &str = "reactive-rooms:scope:host";
 const ROLE_USER: &str = "reactive-rooms:scope:user";
+const ROLE_SPECTATOR: &str = "reactive-rooms:scope:spectator";
 
 impl Role {
     pub fn satisfies(&self, required: &Role) -> bool {
@@ -37,6 +39,7 @@ impl From<String> for Role {
             ROLE_ADMIN => Role::Admin,
             ROLE_HOST => Role::Host,
             ROLE_USER => Role::User,
+            ROLE_SPECTATOR => Role::Spectator,
             _ => Role::Unknown(value),
         }
     }
@@ -48,6 +51,7 @@ impl fmt::Display for Role {
             Role::Admin => f.write_str("Admin"),
             Role::Host => f.write_str("Host"),
             Role::User => f.write_str("User"),
+            Role::Spectator => f.write_str("Spectator"),
             Role::Unknown(s) => write!(f, "Unknown: {s}"),
         }
     }
@@ -0,0 +1,218 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use crate::{
+    message_bus::{OverflowPolicies, OverflowPolicy},
+    read_env_var,
+    websocket::{MessageLimits, RateLimit, ScopeLimits},
+};
+
+/// Runtime settings read from the environment at startup.
+pub struct Config {
+    /// How long a dropped user connection can be resumed before the user
+    /// leaves the room. Zero disables resume.
+    pub user_resume_grace: Duration,
+    /// How long a room outlives a dropped host connection. Zero closes the
+    /// room immediately.
+    pub host_reconnect_grace: Duration,
+    /// What the bus does when a host or user queue is full
+    pub overflow: OverflowPolicies,
+    /// Bearer token `/metrics` is served behind; not served when absent
+    pub metrics_token: Option<String>,
+    /// Size and nesting limits of inbound frames
+    pub message_limits: PerRoomType<MessageLimits>,
+    /// Token buckets guarding against clients that flood the server
+    pub rate_limits: PerRoomType<ScopeLimits>,
+    /// Rate limited frames tolerated before the sender is disconnected;
+    /// one is forgiven every second
+    pub rate_limit_strikes: u32,
+    /// Most broadcasts a room may keep for late joiners
+    pub history_max_messages: usize,
+    /// Room types whose host is replaced by a member instead of closing
+    /// the room when it leaves
+    pub host_migration: HashSet<String>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            user_resume_grace: read_secs("USER_RESUME_GRACE_SECS", 30),
+            host_reconnect_grace: read_secs("HOST_RECONNECT_GRACE_SECS", 30),
+            overflow: OverflowPolicies {
+                to_host: read_overflow_policy("HOST_OVERFLOW_POLICY"),
+                to_user: read_overflow_policy("USER_OVERFLOW_POLICY"),
+            },
+            metrics_token: read_metrics_token(),
+            message_limits: read_message_limits(),
+            rate_limits: read_rate_limits(),
+            rate_limit_strikes: read_env_var("RATE_LIMIT_STRIKES", "20")
+                .parse()
+                .expect("RATE_LIMIT_STRIKES must be a number"),
+            history_max_messages: read_number("HISTORY_MAX_MESSAGES", "1000"),
+            host_migration: read_list("HOST_MIGRATION_ROOM_TYPES"),
+        }
+    }
+}
+
+/// Read `key=[<item>,...]`
+fn read_list(key: &str) -> HashSet<String> {
+    read_env_var(key, "")
+        .trim_matches(|c| c == '[' || c == ']')
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn read_secs(key: &str, default: u64) -> Duration {
+    let value = read_env_var(key, &default.to_string());
+    let secs = value
+        .parse()
+        .unwrap_or_else(|_| panic!("{key} must be a number of seconds"));
+    Duration::from_secs(secs)
+}
+
+fn read_overflow_policy(key: &str) -> OverflowPolicy {
+    match read_env_var(key, "drop_newest").as_str() {
+        "drop_newest" => OverflowPolicy::DropNewest,
+        "drop_oldest" => OverflowPolicy::DropOldest,
+        "disconnect" => OverflowPolicy::Disconnect,
+        "await" => {
+            let value = read_env_var("OVERFLOW_AWAIT_TIMEOUT_MS", "1000");
+            let millis = value
+                .parse()
+                .unwrap_or_else(|_| panic!("OVERFLOW_AWAIT_TIMEOUT_MS must be a number"));
+            OverflowPolicy::AwaitTimeout(Duration::from_millis(millis))
+        }
+        other => panic!("Unknown {key}: {other}"),
+    }
+}
+
+/// Settings that rooms of a type may override
+#[derive(Debug, Clone)]
+pub struct PerRoomType<T> {
+    pub default: T,
+    pub by_room_type: HashMap<String, T>,
+}
+
+impl<T: Copy> PerRoomType<T> {
+    pub fn for_room_type(&self, room_type: &str) -> T {
+        self.by_room_type
+            .get(room_type)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Read `key=[<roomType>.<setting>=<value>,...]`; `apply` sets one setting
+/// on a copy of `default` for that room type
+fn read_overrides<T: Copy>(
+    key: &str,
+    default: T,
+    apply: impl Fn(&mut T, &str, &str),
+) -> PerRoomType<T> {
+    let mut by_room_type: HashMap<String, T> = HashMap::new();
+    let overrides = read_env_var(key, "");
+    for entry in overrides
+        .trim_matches(|c| c == '[' || c == ']')
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+    {
+        let parsed = entry
+            .split_once('=')
+            .and_then(|(name, value)| Some((name.rsplit_once('.')?, value)));
+        let Some(((room_type, setting), value)) = parsed else {
+            panic!("{key} entry must be <roomType>.<setting>=<value>: {entry}");
+        };
+        let settings = by_room_type.entry(room_type.to_string()).or_insert(default);
+        apply(settings, setting, value.trim());
+    }
+    PerRoomType {
+        default,
+        by_room_type,
+    }
+}
+
+/// Defaults from `RATE_LIMIT_<SCOPE>`, overridden per room type by
+/// `RATE_LIMIT_OVERRIDES`
+fn read_rate_limits() -> PerRoomType<ScopeLimits> {
+    let default = ScopeLimits {
+        connection: read_rate_limit("RATE_LIMIT_CONNECTION", "20/40"),
+        host: read_rate_limit("RATE_LIMIT_HOST", "1000/2000"),
+        user: read_rate_limit("RATE_LIMIT_USER", "40/80"),
+        room: read_rate_limit("RATE_LIMIT_ROOM", "500/1000"),
+    };
+    read_overrides("RATE_LIMIT_OVERRIDES", default, |limits, scope, value| {
+        let limit = parse_rate_limit("RATE_LIMIT_OVERRIDES", value);
+        match scope {
+            "connection" => limits.connection = limit,
+            "host" => limits.host = limit,
+            "user" => limits.user = limit,
+            "room" => limits.room = limit,
+            other => panic!("Unknown rate limit scope in RATE_LIMIT_OVERRIDES: {other}"),
+        }
+    })
+}
+
+/// Defaults from `MAX_FRAME_BYTES`, `MAX_MESSAGE_BYTES` and `MAX_JSON_DEPTH`,
+/// overridden per room type by `MESSAGE_LIMIT_OVERRIDES`
+fn read_message_limits() -> PerRoomType<MessageLimits> {
+    let default = MessageLimits {
+        max_frame_bytes: read_number("MAX_FRAME_BYTES", "1048576"),
+        max_message_bytes: read_number("MAX_MESSAGE_BYTES", "1048576"),
+        max_depth: read_number("MAX_JSON_DEPTH", "32"),
+    };
+    read_overrides(
+        "MESSAGE_LIMIT_OVERRIDES",
+        default,
+        |limits, setting, value| {
+            let number = value.parse().unwrap_or_else(|_| {
+                panic!("MESSAGE_LIMIT_OVERRIDES values must be numbers: {value}")
+            });
+            match setting {
+                "frame" => limits.max_frame_bytes = number,
+                "message" => limits.max_message_bytes = number,
+                "depth" => limits.max_depth = number,
+                other => panic!("Unknown message limit in MESSAGE_LIMIT_OVERRIDES: {other}"),
+            }
+        },
+    )
+}
+
+/// `METRICS_TOKEN`, required once `METRICS_ENABLED` turns metrics on
+fn read_metrics_token() -> Option<String> {
+    if read_env_var("METRICS_ENABLED", "false") != "true" {
+        return None;
+    }
+    let token = read_env_var("METRICS_TOKEN", "");
+    if token.is_empty() {
+        panic!("METRICS_TOKEN is required with METRICS_ENABLED=true");
+    }
+    Some(token)
+}
+
+fn read_number(key: &str, default: &str) -> usize {
+    read_env_var(key, default)
+        .parse()
+        .unwrap_or_else(|_| panic!("{key} must be a number"))
+}
+
+fn read_rate_limit(key: &str, default: &str) -> Option<RateLimit> {
+    parse_rate_limit(key, &read_env_var(key, default))
+}
+
+/// `<per second>/<burst>`, or `off`
+fn parse_rate_limit(key: &str, value: &str) -> Option<RateLimit> {
+    if value == "off" {
+        return None;
+    }
+    let limit = value.split_once('/').and_then(|(per_sec, burst)| {
+        Some(RateLimit {
+            per_sec: per_sec.trim().parse().ok()?,
+            burst: burst.trim().parse().ok()?,
+        })
+    });
+    Some(limit.unwrap_or_else(|| panic!("{key} must be <per second>/<burst> or off: {value}")))
+}
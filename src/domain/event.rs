@@ -1,24 +1,109 @@
 use serde::{Deserialize, Serialize};
 
+/// Outbound events use the same SCREAMING_SNAKE_CASE names as inbound ones
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum ToHostEvent {
     JoinRoom,
     LeaveRoom,
     Message,
     Disconnect,
+    Members,
+    Waitlisted,
+    Promoted,
+    RoomState,
+    MessageDropped,
+    Binary,
+    Error,
+    /// Message from an administrator
+    System,
+    /// Another host connected to the room
+    HostJoined,
+    /// Another host disconnected from the room
+    HostLeft,
+    /// Number of spectators watching the room
+    Spectators,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum ToUserEvent {
+    Session,
+    /// Full shared state of the room
+    State,
+    /// Merge patch of a change to the shared state
+    StateChanged,
+    /// Recent host broadcasts, replayed to a joiner
+    History,
     Message,
     Disconnect,
+    HostAway,
+    HostReturned,
+    /// Another user became the host
+    HostChanged,
+    Waitlisted,
+    Promoted,
+    RoomState,
+    MessageDropped,
+    Binary,
+    Error,
+    /// Message from an administrator
+    System,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DisconnectReason {
     Kicked,
     RoomClosed,
     UserClosed,
     NewConnection,
     PingPong,
+    RoomFull,
+    Banned,
+    SlowConsumer,
+    RateLimited,
+    MessageTooLarge,
+    /// The room was handed to another host
+    HostTransferred,
+}
+
+/// Why a frame from a client was rejected, sent back in an `Error` event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ErrorCode {
+    /// The frame could not be decoded at all
+    InvalidJson,
+    /// The event is known but its fields are missing or mistyped
+    InvalidMessage,
+    UnknownEvent,
+    /// The target is not an admitted member of the room
+    UserNotInRoom,
+    /// The frame is too big or nested too deep; the sender is disconnected
+    PayloadTooLarge,
+    /// A rate limit of the connection, user or room was exceeded
+    RateLimited,
+    /// A co-host tried to hand the room over, or the target lacks the Host
+    /// role needed to take it
+    NotEligible,
+    /// Spectators cannot send anything
+    ReadOnly,
+}
+
+/// Why a user connection was refused before the WebSocket upgrade
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum JoinRejection {
+    RoomNotOpen,
+    NotInvited,
+    InvalidSecret,
+    Banned,
+}
+
+impl JoinRejection {
+    pub fn description(self) -> &'static str {
+        match self {
+            JoinRejection::RoomNotOpen => "Room is not open for joining",
+            JoinRejection::NotInvited => "User is not invited to this room",
+            JoinRejection::InvalidSecret => "Invalid join secret",
+            JoinRejection::Banned => "User is banned from this room",
+        }
+    }
 }
@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use super::message::MessagePayload;
+
+/// How much of a room's broadcast history is kept. Whichever limit is hit
+/// first evicts the oldest messages.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryLimits {
+    pub max_messages: usize,
+    /// Messages older than this are dropped; kept until evicted when absent
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+}
+
+impl HistoryLimits {
+    /// Oldest `sent_at` the age limit allows
+    pub fn cutoff(&self, now: u64) -> u64 {
+        self.max_age_secs.map_or(0, |max_age_secs| {
+            now.saturating_sub(max_age_secs.saturating_mul(1000))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    /// Position among every message the room recorded, starting at 1
+    pub id: u64,
+    /// Unix time in milliseconds
+    pub sent_at: u64,
+    pub message: MessagePayload,
+}
+
+/// Ring buffer of the messages a host broadcast to the whole room
+#[derive(Debug, Clone)]
+pub struct MessageHistory {
+    pub limits: HistoryLimits,
+    last_id: u64,
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl MessageHistory {
+    pub fn new(limits: HistoryLimits) -> Self {
+        Self {
+            limits,
+            last_id: 0,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Id of the latest recorded message, 0 before the first
+    pub fn last_id(&self) -> u64 {
+        self.last_id
+    }
+
+    pub fn push(&mut self, message: MessagePayload, now: u64) {
+        self.last_id += 1;
+        self.entries.push_back(HistoryEntry {
+            id: self.last_id,
+            sent_at: now,
+            message,
+        });
+        while self.entries.len() > self.limits.max_messages {
+            self.entries.pop_front();
+        }
+        self.expire(now);
+    }
+
+    /// Messages still within the age limit up to and including id
+    /// `up_to`, oldest first
+    pub fn recent(&self, now: u64, up_to: u64) -> impl Iterator<Item = &HistoryEntry> {
+        let cutoff = self.limits.cutoff(now);
+        self.entries
+            .iter()
+            .skip_while(move |entry| entry.sent_at < cutoff)
+            .take_while(move |entry| entry.id <= up_to)
+    }
+
+    fn expire(&mut self, now: u64) {
+        let cutoff = self.limits.cutoff(now);
+        while self
+            .entries
+            .front()
+            .is_some_and(|entry| entry.sent_at < cutoff)
+        {
+            self.entries.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn ids<'a>(entries: impl Iterator<Item = &'a HistoryEntry>) -> Vec<u64> {
+        entries.map(|entry| entry.id).collect()
+    }
+
+    #[test]
+    fn keeps_the_latest_messages() {
+        let mut history = MessageHistory::new(HistoryLimits {
+            max_messages: 2,
+            max_age_secs: None,
+        });
+        assert_eq!(history.last_id(), 0);
+        for n in 1..=3 {
+            history.push(json!(n), 1_000);
+        }
+        assert_eq!(history.last_id(), 3);
+        assert_eq!(ids(history.recent(1_000, u64::MAX)), vec![2, 3]);
+        assert_eq!(history.recent(1_000, 2).next().unwrap().message, json!(2));
+    }
+
+    #[test]
+    fn drops_messages_past_the_age_limit() {
+        let mut history = MessageHistory::new(HistoryLimits {
+            max_messages: 10,
+            max_age_secs: Some(60),
+        });
+        history.push(json!(1), 0);
+        history.push(json!(2), 30_000);
+        assert_eq!(ids(history.recent(30_000, u64::MAX)), vec![1, 2]);
+        // Old messages are skipped on read before a push evicts them
+        assert_eq!(ids(history.recent(70_000, u64::MAX)), vec![2]);
+
+        history.push(json!(3), 100_000);
+        assert_eq!(ids(history.recent(100_000, u64::MAX)), vec![3]);
+        assert_eq!(ids(history.recent(100_000, 2)), Vec::<u64>::new());
+    }
+}
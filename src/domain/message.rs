@@ -1,19 +1,31 @@
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 
 use super::{
     event::{DisconnectReason, ToHostEvent, ToUserEvent},
+    history::HistoryEntry,
+    room::RoomState,
     user::UserId,
 };
 
 pub type MessagePayload = Value;
 
+/// Version of the WebSocket protocol, announced in `Session` and `Members`.
+/// Version 2 renamed outbound events to SCREAMING_SNAKE_CASE and their
+/// `user_id` field to `userId`.
+pub const PROTOCOL_VERSION: u32 = 2;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ToHostMessage {
     pub event: ToHostEvent,
     pub user_id: UserId,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub message: Option<MessagePayload>,
+    /// Opaque payload of a `Binary` event, relayed without being parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Bytes>,
 }
 
 impl ToHostMessage {
@@ -22,6 +34,7 @@ impl ToHostMessage {
             event: ToHostEvent::JoinRoom,
             user_id,
             message: None,
+            data: None,
         }
     }
 
@@ -30,6 +43,25 @@ impl ToHostMessage {
             event: ToHostEvent::LeaveRoom,
             user_id,
             message: None,
+            data: None,
+        }
+    }
+
+    pub fn waitlisted(user_id: UserId, position: usize) -> Self {
+        Self {
+            event: ToHostEvent::Waitlisted,
+            user_id,
+            message: Some(serde_json::json!({ "position": position })),
+            data: None,
+        }
+    }
+
+    pub fn promoted(user_id: UserId) -> Self {
+        Self {
+            event: ToHostEvent::Promoted,
+            user_id,
+            message: None,
+            data: None,
         }
     }
 
@@ -38,6 +70,7 @@ impl ToHostMessage {
             event: ToHostEvent::Message,
             user_id,
             message: Some(payload),
+            data: None,
         }
     }
 
@@ -46,24 +79,154 @@ impl ToHostMessage {
             event: ToHostEvent::Disconnect,
             user_id,
             message: Some(serde_json::json!({ "reason": reason })),
+            data: None,
+        }
+    }
+
+    pub fn room_state(host_id: UserId, state: RoomState) -> Self {
+        Self {
+            event: ToHostEvent::RoomState,
+            user_id: host_id,
+            message: Some(serde_json::json!({ "state": state })),
+            data: None,
+        }
+    }
+
+    /// Members of the room, the hosts connected to it and how many watch
+    pub fn members(
+        host_id: UserId,
+        users: Vec<UserId>,
+        hosts: Vec<UserId>,
+        spectators: usize,
+    ) -> Self {
+        Self {
+            event: ToHostEvent::Members,
+            user_id: host_id,
+            message: Some(serde_json::json!({
+                "users": users,
+                "hosts": hosts,
+                "spectators": spectators,
+                "protocolVersion": PROTOCOL_VERSION,
+            })),
+            data: None,
+        }
+    }
+
+    pub fn spectators(host_id: UserId, count: usize) -> Self {
+        Self {
+            event: ToHostEvent::Spectators,
+            user_id: host_id,
+            message: Some(serde_json::json!({ "count": count })),
+            data: None,
+        }
+    }
+
+    pub fn host_joined(host_id: UserId) -> Self {
+        Self {
+            event: ToHostEvent::HostJoined,
+            user_id: host_id,
+            message: None,
+            data: None,
+        }
+    }
+
+    pub fn host_left(host_id: UserId) -> Self {
+        Self {
+            event: ToHostEvent::HostLeft,
+            user_id: host_id,
+            message: None,
+            data: None,
+        }
+    }
+
+    /// News about the host connections of the room, with `user_id` the host
+    pub fn is_host_presence(&self) -> bool {
+        matches!(self.event, ToHostEvent::HostJoined | ToHostEvent::HostLeft)
+    }
+
+    /// A host message to `user_id` was lost because their queue was full
+    pub fn message_dropped(user_id: UserId, policy: &str) -> Self {
+        Self {
+            event: ToHostEvent::MessageDropped,
+            user_id,
+            message: Some(serde_json::json!({ "policy": policy })),
+            data: None,
+        }
+    }
+
+    /// A frame from the host was rejected
+    pub fn error(host_id: UserId, error: MessagePayload) -> Self {
+        Self {
+            event: ToHostEvent::Error,
+            user_id: host_id,
+            message: Some(error),
+            data: None,
+        }
+    }
+
+    /// Message from an administrator
+    pub fn system(host_id: UserId, payload: MessagePayload) -> Self {
+        Self {
+            event: ToHostEvent::System,
+            user_id: host_id,
+            message: Some(payload),
+            data: None,
+        }
+    }
+
+    /// Opaque binary frame from `user_id`
+    pub fn binary(user_id: UserId, data: Bytes) -> Self {
+        Self {
+            event: ToHostEvent::Binary,
+            user_id,
+            message: None,
+            data: Some(data),
+        }
+    }
+
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        if !matches!(self.event, ToHostEvent::Disconnect) {
+            return None;
         }
+        let reason = self.message.as_ref()?.get("reason")?;
+        serde_json::from_value(reason.clone()).ok()
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ToUserMessage {
     pub event: ToUserEvent,
     pub user_id: UserId,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub message: Option<MessagePayload>,
+    /// Opaque payload of a `Binary` event, relayed without being parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Bytes>,
 }
 
 impl ToUserMessage {
+    /// First frame of every user connection. `resume_token` is absent when
+    /// session resume is disabled.
+    pub fn session(user_id: UserId, resume_token: Option<&str>, resumed: bool) -> Self {
+        Self {
+            event: ToUserEvent::Session,
+            user_id,
+            message: Some(serde_json::json!({
+                "resumeToken": resume_token,
+                "resumed": resumed,
+                "protocolVersion": PROTOCOL_VERSION,
+            })),
+            data: None,
+        }
+    }
+
     pub fn message(user_id: UserId, payload: MessagePayload) -> Self {
         Self {
             event: ToUserEvent::Message,
             user_id,
             message: Some(payload),
+            data: None,
         }
     }
 
@@ -72,19 +235,229 @@ impl ToUserMessage {
             event: ToUserEvent::Disconnect,
             user_id,
             message: Some(serde_json::json!({ "reason": reason })),
+            data: None,
         }
     }
+
+    pub fn waitlisted(user_id: UserId, position: usize) -> Self {
+        Self {
+            event: ToUserEvent::Waitlisted,
+            user_id,
+            message: Some(serde_json::json!({ "position": position })),
+            data: None,
+        }
+    }
+
+    pub fn promoted(user_id: UserId) -> Self {
+        Self {
+            event: ToUserEvent::Promoted,
+            user_id,
+            message: None,
+            data: None,
+        }
+    }
+
+    /// A message from this user was lost because the host queue was full
+    pub fn message_dropped(user_id: UserId, policy: &str) -> Self {
+        Self {
+            event: ToUserEvent::MessageDropped,
+            user_id,
+            message: Some(serde_json::json!({ "policy": policy })),
+            data: None,
+        }
+    }
+
+    pub fn host_away(user_id: UserId) -> Self {
+        Self {
+            event: ToUserEvent::HostAway,
+            user_id,
+            message: None,
+            data: None,
+        }
+    }
+
+    pub fn host_returned(user_id: UserId) -> Self {
+        Self {
+            event: ToUserEvent::HostReturned,
+            user_id,
+            message: None,
+            data: None,
+        }
+    }
+
+    pub fn host_changed(user_id: UserId, host_id: &UserId) -> Self {
+        Self {
+            event: ToUserEvent::HostChanged,
+            user_id,
+            message: Some(serde_json::json!({ "hostId": host_id })),
+            data: None,
+        }
+    }
+
+    pub fn room_state(user_id: UserId, state: RoomState) -> Self {
+        Self {
+            event: ToUserEvent::RoomState,
+            user_id,
+            message: Some(serde_json::json!({ "state": state })),
+            data: None,
+        }
+    }
+
+    /// Snapshot of the room's shared state as of `version`
+    pub fn state(user_id: UserId, version: u64, state: Map<String, Value>) -> Self {
+        Self {
+            event: ToUserEvent::State,
+            user_id,
+            message: Some(serde_json::json!({ "version": version, "state": state })),
+            data: None,
+        }
+    }
+
+    /// A change to the shared state, as a merge patch that turns version
+    /// `version - 1` into `version`
+    pub fn state_changed(user_id: UserId, version: u64, patch: Map<String, Value>) -> Self {
+        Self {
+            event: ToUserEvent::StateChanged,
+            user_id,
+            message: Some(serde_json::json!({ "version": version, "patch": patch })),
+            data: None,
+        }
+    }
+
+    /// Broadcasts the user missed by joining late, oldest first
+    pub fn history(user_id: UserId, messages: Vec<HistoryEntry>) -> Self {
+        Self {
+            event: ToUserEvent::History,
+            user_id,
+            message: Some(serde_json::json!({ "messages": messages })),
+            data: None,
+        }
+    }
+
+    /// A frame from this user was rejected
+    pub fn error(user_id: UserId, error: MessagePayload) -> Self {
+        Self {
+            event: ToUserEvent::Error,
+            user_id,
+            message: Some(error),
+            data: None,
+        }
+    }
+
+    /// Message from an administrator
+    pub fn system(user_id: UserId, payload: MessagePayload) -> Self {
+        Self {
+            event: ToUserEvent::System,
+            user_id,
+            message: Some(payload),
+            data: None,
+        }
+    }
+
+    /// Opaque binary frame from the host
+    pub fn binary(user_id: UserId, data: Bytes) -> Self {
+        Self {
+            event: ToUserEvent::Binary,
+            user_id,
+            message: None,
+            data: Some(data),
+        }
+    }
+
+    /// Who hosts the room now, for a `HostChanged` event
+    pub fn new_host(&self) -> Option<UserId> {
+        if !matches!(self.event, ToUserEvent::HostChanged) {
+            return None;
+        }
+        let host_id = self.message.as_ref()?.get("hostId")?;
+        serde_json::from_value(host_id.clone()).ok()
+    }
+
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        if !matches!(self.event, ToUserEvent::Disconnect) {
+            return None;
+        }
+        let reason = self.message.as_ref()?.get("reason")?;
+        serde_json::from_value(reason.clone()).ok()
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct UserWebSocketMessage {
-    pub event: String,
-    pub message: MessagePayload,
+/// Inbound events of a user connection
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "event", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum UserWebSocketMessage {
+    /// Confirms every message up to and including `seq`
+    Ack { seq: u64 },
+    Message {
+        #[serde(default)]
+        message: MessagePayload,
+    },
+    /// Ask for a `State` snapshot
+    GetState,
+    #[serde(other)]
+    Unknown,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct HostWebSocketMessage {
-    pub event: String,
-    pub user_id: UserId,
-    pub message: MessagePayload,
+/// Inbound events of a host connection
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "event", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HostWebSocketMessage {
+    /// Confirms every message up to and including `seq`
+    Ack {
+        seq: u64,
+    },
+    Message {
+        #[serde(alias = "userId")]
+        user_id: UserId,
+        #[serde(default)]
+        message: MessagePayload,
+    },
+    /// Kick a member
+    Disconnect {
+        #[serde(alias = "userId")]
+        user_id: UserId,
+    },
+    /// Fan out to every member, or to `userIds` if given, minus `exceptUserIds`
+    Broadcast {
+        #[serde(default, rename = "userIds")]
+        user_ids: Option<Vec<UserId>>,
+        #[serde(default, rename = "exceptUserIds")]
+        except_user_ids: Vec<UserId>,
+        #[serde(default)]
+        message: MessagePayload,
+    },
+    Lock,
+    Unlock,
+    Start,
+    End,
+    /// Permanent when `durationSecs` is absent
+    Ban {
+        #[serde(alias = "userId")]
+        user_id: UserId,
+        #[serde(default, rename = "durationSecs")]
+        duration_secs: Option<u64>,
+    },
+    Unban {
+        #[serde(alias = "userId")]
+        user_id: UserId,
+    },
+    /// Set one key of the shared state; `null` deletes it
+    SetState {
+        key: String,
+        value: Value,
+    },
+    /// Apply a JSON Merge Patch to the shared state
+    PatchState {
+        patch: Map<String, Value>,
+    },
+    DeleteState {
+        key: String,
+    },
+    /// Hand the room to a member with the Host role
+    TransferHost {
+        #[serde(alias = "userId")]
+        user_id: UserId,
+    },
+    #[serde(other)]
+    Unknown,
 }
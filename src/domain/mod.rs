@@ -1,4 +1,5 @@
 pub mod event;
+pub mod history;
 pub mod message;
 pub mod room;
 pub mod user;
@@ -1,7 +1,10 @@
-use super::user::UserId;
+use super::{event::JoinRejection, history::HistoryLimits, user::UserId};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -46,31 +49,458 @@ impl RoomType {
     }
 }
 
+/// Lifecycle of a room. Users may only join an `Open` room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoomState {
+    #[default]
+    Open,
+    /// No new joiners, the host may still unlock
+    Locked,
+    /// Session running, no new joiners
+    InProgress,
+    /// Session ended; the room is removed once the host leaves
+    Closed,
+}
+
+impl RoomState {
+    pub fn can_transition_to(self, next: RoomState) -> bool {
+        use RoomState::*;
+
+        matches!(
+            (self, next),
+            (Open, Locked)
+                | (Locked, Open)
+                | (Open | Locked, InProgress)
+                | (Open | Locked | InProgress, Closed)
+        )
+    }
+
+    pub fn accepts_joiners(self) -> bool {
+        self == RoomState::Open
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Room {
     pub id: RoomId,
     pub host_id: UserId,
+    /// Further users who may connect as hosts alongside `host_id`
+    #[serde(default)]
+    pub co_host_ids: HashSet<UserId>,
     pub room_type: RoomType,
+    /// Maximum number of members; unlimited when absent
+    #[serde(default)]
+    pub max_users: Option<usize>,
+    /// Queue joiners beyond `max_users` instead of rejecting them
+    #[serde(default)]
+    pub waitlist: bool,
+    #[serde(default)]
+    pub state: RoomState,
+    /// Only these users may join; anyone when absent
+    #[serde(default)]
+    pub allowed_users: Option<HashSet<UserId>>,
+    /// Secret joiners must present; not required when absent
+    #[serde(default)]
+    pub join_secret: Option<String>,
+    /// Banned users with the unix time their ban ends; permanent when `None`
+    #[serde(default)]
+    pub bans: HashMap<UserId, Option<u64>>,
+    /// Key/value document the host shares with the members
+    #[serde(default)]
+    pub shared_state: Map<String, Value>,
+    /// Bumped by every change to `shared_state` members are sent
+    #[serde(default)]
+    pub state_version: u64,
+    /// How many recent host broadcasts are replayed to joiners; not kept
+    /// when absent. The messages themselves live in the room storage.
+    #[serde(default)]
+    pub history: Option<HistoryLimits>,
 }
 
 impl Room {
     pub fn new(host_id: UserId, room_type: RoomType) -> Self {
-        Self {
-            id: RoomId::new(),
-            host_id,
-            room_type,
-        }
+        Self::with_id(RoomId::new(), host_id, room_type)
     }
 
     pub fn with_id(id: RoomId, host_id: UserId, room_type: RoomType) -> Self {
         Self {
             id,
             host_id,
+            co_host_ids: HashSet::new(),
             room_type,
+            max_users: None,
+            waitlist: false,
+            state: RoomState::Open,
+            allowed_users: None,
+            join_secret: None,
+            bans: HashMap::new(),
+            shared_state: Map::new(),
+            state_version: 0,
+            history: None,
+        }
+    }
+
+    pub fn with_capacity(mut self, max_users: Option<usize>, waitlist: bool) -> Self {
+        self.max_users = max_users;
+        self.waitlist = waitlist;
+        self
+    }
+
+    pub fn with_access(
+        mut self,
+        allowed_users: Option<HashSet<UserId>>,
+        join_secret: Option<String>,
+    ) -> Self {
+        self.allowed_users = allowed_users;
+        self.join_secret = join_secret;
+        self
+    }
+
+    pub fn with_co_hosts(mut self, co_host_ids: HashSet<UserId>) -> Self {
+        self.set_co_hosts(co_host_ids);
+        self
+    }
+
+    /// Replace the co-hosts. The primary host is never its own co-host.
+    pub fn set_co_hosts(&mut self, mut co_host_ids: HashSet<UserId>) {
+        co_host_ids.remove(&self.host_id);
+        self.co_host_ids = co_host_ids;
+    }
+
+    pub fn with_history(mut self, limits: Option<HistoryLimits>) -> Self {
+        self.history = limits;
+        self
+    }
+
+    /// Check the ban list, invite list and join secret for a joining user
+    pub fn check_access(
+        &self,
+        user_id: &UserId,
+        secret: Option<&str>,
+    ) -> Result<(), JoinRejection> {
+        if self.is_banned(user_id) {
+            return Err(JoinRejection::Banned);
         }
+        if let Some(allowed) = &self.allowed_users
+            && !allowed.contains(user_id)
+        {
+            return Err(JoinRejection::NotInvited);
+        }
+        self.check_secret(secret)
+    }
+
+    /// Spectators face the ban list and join secret, but not the invite
+    /// list, which picks the members
+    pub fn check_spectator_access(
+        &self,
+        user_id: &UserId,
+        secret: Option<&str>,
+    ) -> Result<(), JoinRejection> {
+        if self.is_banned(user_id) {
+            return Err(JoinRejection::Banned);
+        }
+        self.check_secret(secret)
+    }
+
+    fn check_secret(&self, secret: Option<&str>) -> Result<(), JoinRejection> {
+        if let Some(expected) = &self.join_secret
+            && !secret
+                .is_some_and(|secret| constant_time_eq(secret.as_bytes(), expected.as_bytes()))
+        {
+            return Err(JoinRejection::InvalidSecret);
+        }
+        Ok(())
+    }
+
+    /// Ban a user, for `duration` or permanently. Expired bans are dropped.
+    pub fn ban(&mut self, user_id: UserId, duration: Option<Duration>) {
+        let now = unix_now();
+        self.bans
+            .retain(|_, expires_at| expires_at.is_none_or(|expires_at| expires_at > now));
+        let expires_at = duration.map(|duration| now.saturating_add(duration.as_secs()));
+        self.bans.insert(user_id, expires_at);
+    }
+
+    /// Lift a ban. Returns whether the user was banned.
+    pub fn unban(&mut self, user_id: &UserId) -> bool {
+        self.bans.remove(user_id).is_some()
+    }
+
+    pub fn is_banned(&self, user_id: &UserId) -> bool {
+        self.bans
+            .get(user_id)
+            .is_some_and(|expires_at| expires_at.is_none_or(|expires_at| expires_at > unix_now()))
+    }
+
+    /// Apply a JSON Merge Patch (RFC 7396) to the shared state: `null`
+    /// removes a key, objects merge recursively, anything else replaces.
+    /// Returns the part of the patch that changed something, empty if the
+    /// state is as it was.
+    pub fn patch_shared_state(&mut self, patch: &Map<String, Value>) -> Map<String, Value> {
+        let old = Value::Object(self.shared_state.clone());
+        merge_object(&mut self.shared_state, patch);
+        match diff(&old, &Value::Object(self.shared_state.clone())) {
+            Value::Object(diff) => diff,
+            _ => Map::new(),
+        }
+    }
+
+    /// Replace the value of one key; `null` removes it. Returns the merge
+    /// patch from the old state to the new one, empty if nothing changed.
+    pub fn set_shared_key(&mut self, key: String, value: Value) -> Map<String, Value> {
+        let old = self.shared_state.remove(&key).unwrap_or(Value::Null);
+        // Going through a merge drops nested nulls, as a patch would
+        merge_object(
+            &mut self.shared_state,
+            &Map::from_iter([(key.clone(), value)]),
+        );
+        let new = self.shared_state.get(&key).unwrap_or(&Value::Null);
+
+        if old == *new {
+            return Map::new();
+        }
+        Map::from_iter([(key, diff(&old, new))])
+    }
+
+    /// Move to `next` if the lifecycle allows it
+    pub fn transition(&mut self, next: RoomState) -> Result<(), InvalidTransition> {
+        if !self.state.can_transition_to(next) {
+            return Err(InvalidTransition {
+                from: self.state,
+                to: next,
+            });
+        }
+        self.state = next;
+        Ok(())
+    }
+
+    pub fn is_full(&self, user_count: usize) -> bool {
+        self.max_users.is_some_and(|max| user_count >= max)
     }
 
+    /// The primary host or one of the co-hosts
     pub fn is_host(&self, user_id: &UserId) -> bool {
-        self.host_id == *user_id
+        self.host_id == *user_id || self.co_host_ids.contains(user_id)
+    }
+
+    /// Primary host first, then the co-hosts
+    pub fn host_ids(&self) -> impl Iterator<Item = &UserId> {
+        std::iter::once(&self.host_id).chain(&self.co_host_ids)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InvalidTransition {
+    pub from: RoomState,
+    pub to: RoomState,
+}
+
+fn merge_object(target: &mut Map<String, Value>, patch: &Map<String, Value>) {
+    for (key, value) in patch {
+        match value {
+            Value::Null => {
+                target.remove(key);
+            }
+            Value::Object(patch) => {
+                let entry = target
+                    .entry(key.as_str())
+                    .or_insert_with(|| Value::Object(Map::new()));
+                if !entry.is_object() {
+                    *entry = Value::Object(Map::new());
+                }
+                if let Value::Object(entry) = entry {
+                    merge_object(entry, patch);
+                }
+            }
+            value => {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Merge patch that turns `old` into `new`
+fn diff(old: &Value, new: &Value) -> Value {
+    let (Value::Object(old), Value::Object(new)) = (old, new) else {
+        return new.clone();
+    };
+    let mut patch = Map::new();
+    for key in old.keys().filter(|key| !new.contains_key(*key)) {
+        patch.insert(key.clone(), Value::Null);
+    }
+    for (key, value) in new {
+        match old.get(key) {
+            Some(previous) if previous == value => {}
+            Some(previous) => {
+                patch.insert(key.clone(), diff(previous, value));
+            }
+            None => {
+                patch.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    Value::Object(patch)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+pub(crate) fn unix_now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Compare secrets without leaking through timing how much of them matched
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn object(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("not an object"),
+        }
+    }
+
+    fn room() -> Room {
+        Room::new(UserId::new("host"), RoomType::new("game"))
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7396() {
+        let mut room = room();
+        room.shared_state = object(json!({ "a": 1, "b": { "c": 2, "d": 3 }, "e": [1] }));
+        let diff = room.patch_shared_state(&object(json!({
+            "a": null,
+            "b": { "c": null, "f": { "g": null, "h": 4 } },
+            "e": { "i": 5 },
+            "x": null,
+        })));
+        assert_eq!(
+            Value::Object(diff),
+            json!({ "a": null, "b": { "c": null, "f": { "h": 4 } }, "e": { "i": 5 } })
+        );
+        assert_eq!(
+            Value::Object(room.shared_state.clone()),
+            json!({ "b": { "d": 3, "f": { "h": 4 } }, "e": { "i": 5 } })
+        );
+
+        // A patch that leaves the state as it is changes nothing
+        let diff = room.patch_shared_state(&object(json!({ "a": null, "e": { "i": 5 } })));
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn diff_is_the_patch_between_two_values() {
+        let old = json!({ "a": 1, "b": { "c": 2, "d": 3 }, "e": true });
+        let new = json!({ "a": 1, "b": { "c": 4, "d": 3 }, "f": [] });
+        let patch = diff(&old, &new);
+        assert_eq!(patch, json!({ "b": { "c": 4 }, "e": null, "f": [] }));
+
+        let mut merged = object(old);
+        merge_object(&mut merged, &object(patch));
+        assert_eq!(Value::Object(merged), new);
+        assert_eq!(diff(&json!([1]), &json!(2)), json!(2));
+    }
+
+    #[test]
+    fn set_shared_key_returns_only_what_changed() {
+        let mut room = room();
+        let patch = room.set_shared_key("score".into(), json!({ "red": 1, "blue": null }));
+        assert_eq!(Value::Object(patch), json!({ "score": { "red": 1 } }));
+
+        assert!(
+            room.set_shared_key("score".into(), json!({ "red": 1 }))
+                .is_empty()
+        );
+
+        let patch = room.set_shared_key("score".into(), json!({ "blue": 2 }));
+        assert_eq!(
+            Value::Object(patch),
+            json!({ "score": { "red": null, "blue": 2 } })
+        );
+
+        let patch = room.set_shared_key("score".into(), Value::Null);
+        assert_eq!(Value::Object(patch), json!({ "score": null }));
+        assert!(room.shared_state.is_empty());
+    }
+
+    #[test]
+    fn check_access_applies_invites_and_secret() {
+        let invited = UserId::new("invited");
+        let stranger = UserId::new("stranger");
+        let room = room().with_access(
+            Some(HashSet::from([invited.clone()])),
+            Some("secret".into()),
+        );
+
+        assert!(room.check_access(&invited, Some("secret")).is_ok());
+        assert!(matches!(
+            room.check_access(&invited, Some("wrong")),
+            Err(JoinRejection::InvalidSecret)
+        ));
+        assert!(matches!(
+            room.check_access(&invited, None),
+            Err(JoinRejection::InvalidSecret)
+        ));
+        assert!(matches!(
+            room.check_access(&stranger, Some("secret")),
+            Err(JoinRejection::NotInvited)
+        ));
+    }
+
+    #[test]
+    fn bans_apply_until_lifted_or_expired() {
+        let user = UserId::new("user");
+        let mut room = room();
+
+        room.ban(user.clone(), None);
+        assert!(matches!(
+            room.check_access(&user, None),
+            Err(JoinRejection::Banned)
+        ));
+        assert!(room.unban(&user));
+        assert!(!room.unban(&user));
+        assert!(room.check_access(&user, None).is_ok());
+
+        room.ban(user.clone(), Some(Duration::from_secs(60)));
+        assert!(room.is_banned(&user));
+        room.bans.insert(user.clone(), Some(unix_now() - 1));
+        assert!(room.check_access(&user, None).is_ok());
+    }
+
+    #[test]
+    fn spectators_face_bans_and_secret_but_not_invites() {
+        let stranger = UserId::new("stranger");
+        let mut room = room().with_access(
+            Some(HashSet::from([UserId::new("invited")])),
+            Some("secret".into()),
+        );
+
+        assert!(
+            room.check_spectator_access(&stranger, Some("secret"))
+                .is_ok()
+        );
+        assert!(matches!(
+            room.check_spectator_access(&stranger, None),
+            Err(JoinRejection::InvalidSecret)
+        ));
+        room.ban(stranger.clone(), None);
+        assert!(matches!(
+            room.check_spectator_access(&stranger, Some("secret")),
+            Err(JoinRejection::Banned)
+        ));
     }
 }
@@ -1,11 +1,13 @@
 mod api;
 mod auth;
+mod config;
 mod domain;
 mod message_bus;
+mod metrics;
 mod storage;
 mod websocket;
 
-use api::{not_found, ping};
+use api::{metrics, not_found, ping};
 use axum::{
     Router,
     extract::DefaultBodyLimit,
@@ -17,20 +19,27 @@ use axum_keycloak_auth::{
     extract::{QueryParamTokenExtractor, TokenExtractor},
     layer::KeycloakAuthLayer,
 };
-use message_bus::MessageBus;
+use config::Config;
+use message_bus::{Bus, LocalMessageBus, MeshAuth, MeshMessageBus, OverflowPolicies};
 use mimalloc::MiMalloc;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
-use storage::RoomStorage;
+use storage::{InMemoryRoomStorage, RoomStore, SqliteRoomStorage};
 use tokio::net::TcpListener;
 use tower_http::{cors::CorsLayer, timeout::TimeoutLayer, trace::TraceLayer};
+use websocket::{ConnectionRegistry, RateLimiter, SessionRegistry};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
 pub struct AppState {
-    pub storage: RoomStorage,
-    pub message_bus: MessageBus,
+    pub storage: Box<dyn RoomStore>,
+    pub message_bus: Box<dyn Bus>,
+    pub sessions: SessionRegistry,
+    pub connections: ConnectionRegistry,
+    pub rate_limiter: RateLimiter,
+    pub config: Config,
 }
 
 pub struct Server;
@@ -44,6 +53,62 @@ impl Server {
         TcpListener::bind(addr).await.expect("the address is busy")
     }
 
+    /// Picks the room storage backend from `STORAGE` (`memory` or `sqlite`).
+    /// Mesh nodes must share a SQLite file, and each needs a `NODE_ID` that
+    /// survives restarts: a node clears the rows it owned when it opens the
+    /// database, so rows of a node that came back under a new name linger.
+    fn init_storage() -> Box<dyn RoomStore> {
+        let mesh = read_env_var("BUS", "local") == "mesh";
+        match read_env_var("STORAGE", "memory").as_str() {
+            "memory" if mesh => panic!("BUS=mesh requires STORAGE=sqlite shared by all nodes"),
+            "memory" => Box::new(InMemoryRoomStorage::new()),
+            "sqlite" => {
+                let path = read_env_var("SQLITE_PATH", "rooms.db");
+                let node_id = if mesh {
+                    let node_id = read_env_var("NODE_ID", "");
+                    if node_id.is_empty() {
+                        panic!("NODE_ID is required with BUS=mesh");
+                    }
+                    node_id
+                } else {
+                    read_env_var("NODE_ID", &read_env_var("HOSTNAME", "local"))
+                };
+                Box::new(
+                    SqliteRoomStorage::open(&path, &node_id)
+                        .expect("Failed to open SQLite storage"),
+                )
+            }
+            other => panic!("Unknown STORAGE backend: {other}"),
+        }
+    }
+
+    /// Picks the message bus from `BUS` (`local` or `mesh`).
+    async fn init_message_bus(overflow: OverflowPolicies) -> Box<dyn Bus> {
+        match read_env_var("BUS", "local").as_str() {
+            "local" => Box::new(LocalMessageBus::new(overflow)),
+            "mesh" => {
+                let listen = read_env_var("MESH_LISTEN", "127.0.0.1:4000");
+                let secret = read_env_var("MESH_SECRET", "");
+                if secret.is_empty() {
+                    panic!("MESH_SECRET is required with BUS=mesh");
+                }
+                let peers = read_env_var("MESH_PEERS", "")
+                    .trim_matches(|c| c == '[' || c == ']')
+                    .split(',')
+                    .map(|s| s.trim())
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect();
+                Box::new(
+                    MeshMessageBus::start(&listen, peers, MeshAuth::new(&secret), overflow)
+                        .await
+                        .expect("Failed to start mesh bus"),
+                )
+            }
+            other => panic!("Unknown BUS backend: {other}"),
+        }
+    }
+
     fn init_router(state: Arc<AppState>) -> Router {
         let cors = Self::init_cors();
         let audience = auth::keycloak_audience();
@@ -69,10 +134,15 @@ impl Server {
         let rest_routes = api::routes::room_routes();
 
         // Public routes
-        let public_routes = Router::new()
+        let mut public_routes = Router::new()
             .route("/ping", routing::get(ping))
             .route("/health", routing::get(ping));
 
+        // Metrics are opt-in and always behind the METRICS_TOKEN bearer token
+        if state.config.metrics_token.is_some() {
+            public_routes = public_routes.route("/metrics", routing::get(metrics));
+        }
+
         Router::new()
             .merge(public_routes)
             .merge(rest_routes)
@@ -112,7 +182,13 @@ impl Server {
             .collect::<Vec<_>>();
 
         CorsLayer::new()
-            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ])
             .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::ACCEPT])
             .allow_origin(origins)
     }
@@ -121,9 +197,24 @@ impl Server {
         Self::init_tracing();
 
         auth::init_keycloak().expect("Failed to initialize Keycloak");
+        let config = Config::from_env();
         let state = Arc::new(AppState {
-            storage: RoomStorage::new(),
-            message_bus: MessageBus::new(),
+            storage: Self::init_storage(),
+            message_bus: Self::init_message_bus(config.overflow).await,
+            sessions: SessionRegistry::new(),
+            connections: ConnectionRegistry::new(),
+            rate_limiter: RateLimiter::new(),
+            config,
+        });
+
+        // Idle rate limit buckets are forgotten so the maps stay small
+        let pruned = state.clone();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                tick.tick().await;
+                pruned.rate_limiter.prune();
+            }
         });
 
         let listener = Self::init_tcp_listener().await;
@@ -131,7 +222,12 @@ impl Server {
 
         tracing::info!("listening on http://{}", listener.local_addr().unwrap());
 
-        axum::serve(listener, router).await.unwrap()
+        axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap()
     }
 }
 
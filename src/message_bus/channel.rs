@@ -0,0 +1,369 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::Notify;
+use tokio::time::{Instant, sleep_until};
+
+use super::OverflowPolicy;
+
+/// Bounded single-consumer queue between the bus and a socket task.
+///
+/// Works like `tokio::sync::mpsc`, except that the sender picks what happens
+/// when the queue is full, including evicting the oldest message.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            backlog: VecDeque::new(),
+            draining: false,
+            senders: 1,
+            receiver_alive: true,
+            overflowed: false,
+        }),
+        capacity,
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Result of a send on a full or closed queue
+pub enum SendOutcome<T> {
+    Sent,
+    /// Waiting for room under `OverflowPolicy::AwaitTimeout`; the final
+    /// outcome is reported later
+    Deferred,
+    /// The queue stayed full; this message was not queued
+    Dropped(T),
+    /// This message was queued in place of the returned oldest one
+    Evicted(T),
+    /// The consumer was cut off; its queue was discarded
+    Overflowed(T),
+    /// The receiver is gone
+    Closed(T),
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    /// Messages waiting for room under `OverflowPolicy::AwaitTimeout`,
+    /// oldest first, with the time they are given up
+    backlog: VecDeque<(T, Instant)>,
+    /// Whether a task is moving the backlog into the queue
+    draining: bool,
+    senders: usize,
+    receiver_alive: bool,
+    /// Set by `OverflowPolicy::Disconnect`; the receiver sees the end of the queue
+    overflowed: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    readable: Notify,
+    writable: Notify,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.readable.notify_one();
+        }
+    }
+}
+
+impl<T: Send + 'static> Sender<T> {
+    /// Queue a message without ever waiting on the caller's task. Under
+    /// `OverflowPolicy::AwaitTimeout` a message that finds the queue full
+    /// is held back in order and moved in as room frees up; `on_outcome`
+    /// hears whether it made it in time.
+    pub fn send(
+        &self,
+        msg: T,
+        policy: OverflowPolicy,
+        on_outcome: impl Fn(SendOutcome<T>) + Send + 'static,
+    ) -> SendOutcome<T> {
+        let OverflowPolicy::AwaitTimeout(limit) = policy else {
+            return self.push(msg, policy);
+        };
+
+        let mut state = self.shared.lock();
+        if !state.receiver_alive || state.overflowed {
+            return SendOutcome::Closed(msg);
+        }
+        // Later messages never overtake held back ones
+        if state.backlog.is_empty() && state.queue.len() < self.shared.capacity {
+            state.queue.push_back(msg);
+            drop(state);
+            self.shared.readable.notify_one();
+            return SendOutcome::Sent;
+        }
+
+        state.backlog.push_back((msg, Instant::now() + limit));
+        if !state.draining {
+            state.draining = true;
+            tokio::spawn(drain_backlog(self.shared.clone(), on_outcome));
+        }
+        SendOutcome::Deferred
+    }
+}
+
+impl<T> Sender<T> {
+    /// Queue a message if there is room, dropping it otherwise
+    pub fn try_send(&self, msg: T) -> SendOutcome<T> {
+        self.push(msg, OverflowPolicy::DropNewest)
+    }
+
+    /// Queue a message regardless of capacity, for control messages
+    pub fn force_send(&self, msg: T) {
+        let mut state = self.shared.lock();
+        if state.receiver_alive && !state.overflowed {
+            state.queue.push_back(msg);
+            drop(state);
+            self.shared.readable.notify_one();
+        }
+    }
+
+    fn push(&self, msg: T, policy: OverflowPolicy) -> SendOutcome<T> {
+        let mut state = self.shared.lock();
+        if !state.receiver_alive || state.overflowed {
+            return SendOutcome::Closed(msg);
+        }
+
+        let outcome = if state.queue.len() < self.shared.capacity {
+            state.queue.push_back(msg);
+            SendOutcome::Sent
+        } else {
+            match policy {
+                OverflowPolicy::DropOldest => {
+                    let evicted = state.queue.pop_front();
+                    state.queue.push_back(msg);
+                    match evicted {
+                        Some(evicted) => SendOutcome::Evicted(evicted),
+                        None => SendOutcome::Sent,
+                    }
+                }
+                OverflowPolicy::Disconnect => {
+                    state.queue.clear();
+                    state.overflowed = true;
+                    SendOutcome::Overflowed(msg)
+                }
+                OverflowPolicy::DropNewest | OverflowPolicy::AwaitTimeout(_) => {
+                    return SendOutcome::Dropped(msg);
+                }
+            }
+        };
+
+        drop(state);
+        self.shared.readable.notify_one();
+        outcome
+    }
+}
+
+/// Move held back messages into the queue as the receiver makes room,
+/// dropping those whose wait ran out. Ends once the backlog is empty.
+async fn drain_backlog<T>(shared: Arc<Shared<T>>, on_outcome: impl Fn(SendOutcome<T>)) {
+    loop {
+        let writable = shared.writable.notified();
+        let mut outcomes = Vec::new();
+        let next_deadline = {
+            let mut state = shared.lock();
+            if !state.receiver_alive || state.overflowed {
+                outcomes.extend(
+                    state
+                        .backlog
+                        .drain(..)
+                        .map(|(msg, _)| SendOutcome::Closed(msg)),
+                );
+            }
+            while state.queue.len() < shared.capacity
+                && let Some((msg, _)) = state.backlog.pop_front()
+            {
+                state.queue.push_back(msg);
+                outcomes.push(SendOutcome::Sent);
+            }
+            let now = Instant::now();
+            while state
+                .backlog
+                .front()
+                .is_some_and(|(_, deadline)| *deadline <= now)
+            {
+                if let Some((msg, _)) = state.backlog.pop_front() {
+                    outcomes.push(SendOutcome::Dropped(msg));
+                }
+            }
+            let next_deadline = state.backlog.front().map(|(_, deadline)| *deadline);
+            state.draining = next_deadline.is_some();
+            next_deadline
+        };
+
+        if outcomes
+            .iter()
+            .any(|outcome| matches!(outcome, SendOutcome::Sent))
+        {
+            shared.readable.notify_one();
+        }
+        outcomes.into_iter().for_each(&on_outcome);
+
+        let Some(deadline) = next_deadline else {
+            return;
+        };
+        tokio::select! {
+            _ = writable => {}
+            _ = sleep_until(deadline) => {}
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.receiver_alive = false;
+        state.queue.clear();
+        drop(state);
+        self.shared.writable.notify_waiters();
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Next message, or `None` once every sender is gone or the queue
+    /// overflowed under `OverflowPolicy::Disconnect`. Cancel safe.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let readable = self.shared.readable.notified();
+            {
+                let mut state = self.shared.lock();
+                if let Some(msg) = state.queue.pop_front() {
+                    drop(state);
+                    self.shared.writable.notify_waiters();
+                    return Some(msg);
+                }
+                if state.senders == 0 || state.overflowed {
+                    return None;
+                }
+            }
+            readable.await;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        let msg = self.shared.lock().queue.pop_front();
+        if msg.is_some() {
+            self.shared.writable.notify_waiters();
+        }
+        msg
+    }
+
+    /// Whether every sender is gone, e.g. a newer registration took over
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().senders == 0
+    }
+
+    /// Whether a queued message matches, without taking it off the queue
+    pub fn any_queued(&self, pred: impl Fn(&T) -> bool) -> bool {
+        self.shared.lock().queue.iter().any(pred)
+    }
+
+    /// Whether the queue was cut off because the consumer fell behind
+    pub fn overflowed(&self) -> bool {
+        self.shared.lock().overflowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    const WAIT: OverflowPolicy = OverflowPolicy::AwaitTimeout(Duration::from_millis(200));
+
+    fn ignore(_: SendOutcome<u32>) {}
+
+    #[tokio::test]
+    async fn await_timeout_never_blocks_the_sender() {
+        let (tx, mut rx) = channel(1);
+        assert!(matches!(tx.send(1, WAIT, ignore), SendOutcome::Sent));
+        assert!(matches!(tx.send(2, WAIT, ignore), SendOutcome::Deferred));
+        assert!(matches!(tx.send(3, WAIT, ignore), SendOutcome::Deferred));
+
+        // Held back messages keep their order as room frees up
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.recv().await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn await_timeout_drops_after_the_limit() {
+        let (tx, mut rx) = channel(1);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let report = {
+            let dropped = dropped.clone();
+            move |outcome| {
+                if let SendOutcome::Dropped(msg) = outcome {
+                    assert_eq!(msg, 2);
+                    dropped.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        };
+        tx.send(1, WAIT, report.clone());
+        tx.send(2, WAIT, report);
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(dropped.load(Ordering::SeqCst), 1);
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn force_send_ignores_capacity() {
+        let (tx, mut rx) = channel(1);
+        assert!(matches!(tx.try_send(1), SendOutcome::Sent));
+        assert!(matches!(tx.try_send(2), SendOutcome::Dropped(2)));
+        tx.force_send(3);
+        assert_eq!(rx.try_recv(), Some(1));
+        assert_eq!(rx.try_recv(), Some(3));
+    }
+
+    #[test]
+    fn disconnect_policy_cuts_the_receiver_off() {
+        let (tx, rx) = channel(1);
+        tx.try_send(1);
+        assert!(matches!(
+            tx.send(2, OverflowPolicy::Disconnect, ignore),
+            SendOutcome::Overflowed(2)
+        ));
+        assert!(rx.overflowed());
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+
+use super::{
+    Bus, OverflowPolicies, Receiver,
+    channel::{self, SendOutcome, Sender},
+};
+use crate::{
+    domain::{
+        event::{DisconnectReason, ToHostEvent, ToUserEvent},
+        message::{ToHostMessage, ToUserMessage},
+        user::UserId,
+    },
+    metrics::METRICS,
+};
+
+const CHANNEL_BUFFER: usize = 256;
+
+type HostChannels = DashMap<String, HashMap<UserId, Sender<ToHostMessage>>>;
+type UserChannels = DashMap<String, Sender<ToUserMessage>>;
+
+/// Process-local bus: every host and user must be connected to this instance.
+pub struct LocalMessageBus {
+    /// roomId (string) -> sender for messages to each host of the room
+    host_channels: Arc<HostChannels>,
+    /// "userId:roomId" -> sender for messages to user
+    user_channels: Arc<UserChannels>,
+    /// roomId (string) -> sender for messages to each spectator of the room
+    spectator_channels: DashMap<String, HashMap<UserId, Sender<ToUserMessage>>>,
+    overflow: OverflowPolicies,
+}
+
+impl Default for LocalMessageBus {
+    fn default() -> Self {
+        Self::new(OverflowPolicies::default())
+    }
+}
+
+impl LocalMessageBus {
+    pub fn new(overflow: OverflowPolicies) -> Self {
+        Self {
+            host_channels: Arc::default(),
+            user_channels: Arc::default(),
+            spectator_channels: DashMap::new(),
+            overflow,
+        }
+    }
+
+    /// Deliver to the locally connected hosts of a room, or only to
+    /// `host_id` when given. Hands the message back if none of them is
+    /// connected to this instance. Never waits for a full queue.
+    pub fn deliver_to_host(
+        &self,
+        room_id: &str,
+        host_id: Option<&UserId>,
+        msg: ToHostMessage,
+    ) -> Result<(), ToHostMessage> {
+        let senders: Vec<Sender<ToHostMessage>> = match self.host_channels.get(room_id) {
+            Some(hosts) => hosts
+                .iter()
+                .filter(|(id, _)| host_id.is_none_or(|host_id| *id == host_id))
+                .map(|(_, tx)| tx.clone())
+                .collect(),
+            None => Vec::new(),
+        };
+        if senders.is_empty() {
+            return Err(msg);
+        }
+
+        if msg.disconnect_reason().is_some() {
+            senders.iter().for_each(|tx| tx.force_send(msg.clone()));
+            return Ok(());
+        }
+
+        let policy = self.overflow.to_host;
+        let report = {
+            let user_channels = self.user_channels.clone();
+            let room_id = room_id.to_string();
+            move |outcome: SendOutcome<ToHostMessage>| {
+                let Some(dropped) = count_delivery("to_host", outcome) else {
+                    return;
+                };
+                // The user whose message was lost hears about it
+                if matches!(dropped.event, ToHostEvent::Message) {
+                    tracing::warn!(
+                        "Dropped message from user {} to host of room {}",
+                        dropped.user_id.as_str(),
+                        room_id
+                    );
+                    notify_user(
+                        &user_channels,
+                        &dropped.user_id,
+                        &room_id,
+                        ToUserMessage::message_dropped(dropped.user_id.clone(), policy.name()),
+                    );
+                }
+            }
+        };
+        for tx in senders {
+            report(tx.send(msg.clone(), policy, report.clone()));
+        }
+        Ok(())
+    }
+
+    /// Deliver to a locally connected user. Hands the message back if the
+    /// user is not connected to this instance. Never waits for a full queue.
+    pub fn deliver_to_user(
+        &self,
+        user_id: &UserId,
+        room_id: &str,
+        msg: ToUserMessage,
+    ) -> Result<(), ToUserMessage> {
+        let key = user_channel_key(user_id, room_id);
+        let Some(tx) = self.user_channels.get(&key).map(|tx| tx.clone()) else {
+            return Err(msg);
+        };
+        if msg.disconnect_reason().is_some() {
+            tx.force_send(msg);
+            return Ok(());
+        }
+
+        let policy = self.overflow.to_user;
+        let report = {
+            let host_channels = self.host_channels.clone();
+            let room_id = room_id.to_string();
+            move |outcome: SendOutcome<ToUserMessage>| {
+                let Some(dropped) = count_delivery("to_user", outcome) else {
+                    return;
+                };
+                // The host whose message was lost hears about it
+                if matches!(dropped.event, ToUserEvent::Message) {
+                    tracing::warn!(
+                        "Dropped message from host of room {} to user {}",
+                        room_id,
+                        dropped.user_id.as_str()
+                    );
+                    notify_host(
+                        &host_channels,
+                        &room_id,
+                        ToHostMessage::message_dropped(dropped.user_id.clone(), policy.name()),
+                    );
+                }
+            }
+        };
+        report(tx.send(msg, policy, report.clone()));
+        Ok(())
+    }
+
+    /// Deliver to the locally connected spectators of a room, or only to
+    /// `spectator` when given. Nobody hears about messages a spectator's
+    /// full queue drops. Never waits for a full queue.
+    pub fn deliver_to_spectators(
+        &self,
+        room_id: &str,
+        spectator: Option<&UserId>,
+        msg: ToUserMessage,
+    ) {
+        let spectators: Vec<(UserId, Sender<ToUserMessage>)> =
+            match self.spectator_channels.get(room_id) {
+                Some(spectators) => spectators
+                    .iter()
+                    .filter(|(user_id, _)| spectator.is_none_or(|s| s == *user_id))
+                    .map(|(user_id, tx)| (user_id.clone(), tx.clone()))
+                    .collect(),
+                None => return,
+            };
+
+        let policy = self.overflow.to_user;
+        let report = |outcome: SendOutcome<ToUserMessage>| {
+            count_delivery("to_user", outcome);
+        };
+        for (user_id, tx) in spectators {
+            let msg = ToUserMessage {
+                user_id,
+                ..msg.clone()
+            };
+            if msg.disconnect_reason().is_some() {
+                tx.force_send(msg);
+                continue;
+            }
+            report(tx.send(msg, policy, report));
+        }
+    }
+}
+
+/// Best-effort notice that is itself never reported when dropped
+fn notify_host(host_channels: &HostChannels, room_id: &str, msg: ToHostMessage) {
+    if let Some(hosts) = host_channels.get(room_id) {
+        for tx in hosts.values() {
+            tx.try_send(msg.clone());
+        }
+    }
+}
+
+/// Best-effort notice that is itself never reported when dropped
+fn notify_user(user_channels: &UserChannels, user_id: &UserId, room_id: &str, msg: ToUserMessage) {
+    if let Some(tx) = user_channels.get(&user_channel_key(user_id, room_id)) {
+        tx.try_send(msg);
+    }
+}
+
+#[async_trait]
+impl Bus for LocalMessageBus {
+    fn register_host(&self, room_id: &str, host_id: &UserId) -> Receiver<ToHostMessage> {
+        let (tx, rx) = channel::channel(CHANNEL_BUFFER);
+        self.host_channels
+            .entry(room_id.to_string())
+            .or_default()
+            .insert(host_id.clone(), tx);
+        rx
+    }
+
+    fn unregister_host(&self, room_id: &str, host_id: &UserId) {
+        self.host_channels.remove_if_mut(room_id, |_, hosts| {
+            hosts.remove(host_id);
+            hosts.is_empty()
+        });
+    }
+
+    async fn send_to_host(&self, room_id: &str, msg: ToHostMessage) {
+        let _ = self.deliver_to_host(room_id, None, msg);
+    }
+
+    async fn send_to_one_host(&self, room_id: &str, host_id: &UserId, msg: ToHostMessage) {
+        let _ = self.deliver_to_host(room_id, Some(host_id), msg);
+    }
+
+    fn register_user(&self, user_id: &UserId, room_id: &str) -> Receiver<ToUserMessage> {
+        let key = user_channel_key(user_id, room_id);
+        let (tx, rx) = channel::channel(CHANNEL_BUFFER);
+
+        if let Some(old_tx) = self.user_channels.insert(key, tx) {
+            old_tx.force_send(ToUserMessage::disconnect(
+                user_id.clone(),
+                DisconnectReason::NewConnection,
+            ));
+        }
+
+        rx
+    }
+
+    fn unregister_user(&self, user_id: &UserId, room_id: &str) {
+        let key = user_channel_key(user_id, room_id);
+        self.user_channels.remove(&key);
+    }
+
+    async fn send_to_user(&self, user_id: &UserId, room_id: &str, msg: ToUserMessage) {
+        let _ = self.deliver_to_user(user_id, room_id, msg);
+    }
+
+    fn register_spectator(&self, room_id: &str, user_id: &UserId) -> Receiver<ToUserMessage> {
+        let (tx, rx) = channel::channel(CHANNEL_BUFFER);
+        self.spectator_channels
+            .entry(room_id.to_string())
+            .or_default()
+            .insert(user_id.clone(), tx);
+        rx
+    }
+
+    fn unregister_spectator(&self, room_id: &str, user_id: &UserId) {
+        self.spectator_channels
+            .remove_if_mut(room_id, |_, spectators| {
+                spectators.remove(user_id);
+                spectators.is_empty()
+            });
+    }
+
+    async fn send_to_spectators(&self, room_id: &str, msg: ToUserMessage) {
+        self.deliver_to_spectators(room_id, None, msg);
+    }
+
+    async fn send_to_one_spectator(&self, room_id: &str, user_id: &UserId, msg: ToUserMessage) {
+        self.deliver_to_spectators(room_id, Some(user_id), msg);
+    }
+}
+
+/// Count the outcome of a send and return the message that was lost, if
+/// the sender should be told about it.
+fn count_delivery<T>(direction: &str, outcome: SendOutcome<T>) -> Option<T> {
+    let dropped = match outcome {
+        SendOutcome::Sent => {
+            METRICS
+                .messages_routed
+                .with_label_values(&[direction])
+                .inc();
+            return None;
+        }
+        SendOutcome::Deferred => return None,
+        SendOutcome::Closed(_) => None,
+        SendOutcome::Evicted(msg) => {
+            // The new message made it in place of the evicted one
+            METRICS
+                .messages_routed
+                .with_label_values(&[direction])
+                .inc();
+            Some(msg)
+        }
+        SendOutcome::Dropped(msg) | SendOutcome::Overflowed(msg) => Some(msg),
+    };
+    METRICS
+        .messages_dropped
+        .with_label_values(&[direction])
+        .inc();
+    dropped
+}
+
+fn user_channel_key(user_id: &UserId, room_id: &str) -> String {
+    format!("{}:{}", user_id.as_str(), room_id)
+}
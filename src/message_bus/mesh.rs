@@ -0,0 +1,610 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::timeout,
+};
+use uuid::Uuid;
+
+use super::{Bus, LocalMessageBus, OverflowPolicies, Receiver};
+use crate::{
+    domain::{
+        event::DisconnectReason,
+        message::{MessagePayload, ToHostMessage, ToUserMessage},
+        user::UserId,
+    },
+    metrics::METRICS,
+};
+
+const PEER_BUFFER: usize = 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest handshake line: a nonce and a hex HMAC-SHA256 tag
+const HANDSHAKE_LINE_LIMIT: u64 = 256;
+
+/// Wire format between mesh nodes: one JSON object per line.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+enum Envelope {
+    /// For every host of the room, or only `host_id` when given
+    #[serde(rename_all = "camelCase")]
+    ToHost {
+        room_id: String,
+        #[serde(default)]
+        host_id: Option<UserId>,
+        msg: ToHostMessage,
+    },
+    #[serde(rename_all = "camelCase")]
+    ToUser {
+        user_id: UserId,
+        room_id: String,
+        msg: ToUserMessage,
+    },
+    /// For every spectator of the room, or only `user_id` when given
+    #[serde(rename_all = "camelCase")]
+    ToSpectators {
+        room_id: String,
+        #[serde(default)]
+        user_id: Option<UserId>,
+        msg: ToUserMessage,
+    },
+    #[serde(rename_all = "camelCase")]
+    Broadcast {
+        room_id: String,
+        user_ids: Vec<UserId>,
+        payload: MessagePayload,
+    },
+}
+
+/// A line queued for one peer link
+struct Outgoing {
+    line: Arc<str>,
+    /// Set for session replacements, which only go out on the connection
+    /// they were queued for: replayed after an outage, they could kick a
+    /// socket the user opened on the peer since.
+    replaces_session_at: Option<Instant>,
+}
+
+/// Shared secret of the mesh. Both sides of a link prove they know it by
+/// signing a nonce chosen by the other side, so the secret never goes over
+/// the wire and a recorded handshake cannot be replayed.
+#[derive(Clone)]
+pub struct MeshAuth {
+    key: hmac::Key,
+}
+
+impl MeshAuth {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        }
+    }
+
+    /// The role is part of the signed data, so an acceptor's answer cannot
+    /// be reflected back as a connector's one
+    fn sign(&self, role: &str, nonce: &str) -> String {
+        hex::encode(hmac::sign(&self.key, format!("{role}:{nonce}").as_bytes()))
+    }
+
+    /// Constant-time check of a tag received from the other side
+    fn verify(&self, role: &str, nonce: &str, tag: &str) -> bool {
+        hex::decode(tag).is_ok_and(|tag| {
+            hmac::verify(&self.key, format!("{role}:{nonce}").as_bytes(), &tag).is_ok()
+        })
+    }
+}
+
+/// Multi-node bus: messages for sockets that are not connected to this
+/// instance are relayed to every peer over a TCP mesh. Links are
+/// authenticated with [`MeshAuth`] before any envelope is accepted. Peers
+/// only deliver relayed messages locally, so there is no re-forwarding.
+pub struct MeshMessageBus {
+    local: Arc<LocalMessageBus>,
+    peers: Vec<mpsc::Sender<Outgoing>>,
+}
+
+impl MeshMessageBus {
+    /// Bind the mesh listener on `listen` and start a link to every peer.
+    pub async fn start(
+        listen: &str,
+        peers: Vec<String>,
+        auth: MeshAuth,
+        overflow: OverflowPolicies,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(listen).await?;
+        tracing::info!("Mesh bus listening on {}", listener.local_addr()?);
+        Ok(Self::with_listener(listener, peers, auth, overflow))
+    }
+
+    fn with_listener(
+        listener: TcpListener,
+        peers: Vec<String>,
+        auth: MeshAuth,
+        overflow: OverflowPolicies,
+    ) -> Self {
+        let local = Arc::new(LocalMessageBus::new(overflow));
+        tokio::spawn(accept_peers(listener, local.clone(), auth.clone()));
+
+        let peers = peers
+            .into_iter()
+            .map(|addr| {
+                let (tx, rx) = mpsc::channel(PEER_BUFFER);
+                tokio::spawn(peer_link(addr, auth.clone(), rx));
+                tx
+            })
+            .collect();
+
+        Self { local, peers }
+    }
+
+    fn forward(&self, envelope: &Envelope) {
+        let replaces_session = matches!(
+            envelope,
+            Envelope::ToUser { msg, .. }
+                if msg.disconnect_reason() == Some(DisconnectReason::NewConnection)
+        );
+        let line: Arc<str> = match serde_json::to_string(envelope) {
+            Ok(mut json) => {
+                json.push('\n');
+                json.into()
+            }
+            Err(e) => {
+                tracing::error!("Failed to serialize mesh envelope: {}", e);
+                return;
+            }
+        };
+
+        let queued_at = Instant::now();
+        for peer in &self.peers {
+            let outgoing = Outgoing {
+                line: line.clone(),
+                replaces_session_at: replaces_session.then_some(queued_at),
+            };
+            if peer.try_send(outgoing).is_err() {
+                METRICS.messages_dropped.with_label_values(&["peer"]).inc();
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Bus for MeshMessageBus {
+    fn register_host(&self, room_id: &str, host_id: &UserId) -> Receiver<ToHostMessage> {
+        self.local.register_host(room_id, host_id)
+    }
+
+    fn unregister_host(&self, room_id: &str, host_id: &UserId) {
+        self.local.unregister_host(room_id, host_id);
+    }
+
+    /// Co-hosts may be spread over several nodes, so the message is relayed
+    /// even when a local host got it
+    async fn send_to_host(&self, room_id: &str, msg: ToHostMessage) {
+        let envelope = Envelope::ToHost {
+            room_id: room_id.to_string(),
+            host_id: None,
+            msg: msg.clone(),
+        };
+        let _ = self.local.deliver_to_host(room_id, None, msg);
+        self.forward(&envelope);
+    }
+
+    async fn send_to_one_host(&self, room_id: &str, host_id: &UserId, msg: ToHostMessage) {
+        if let Err(msg) = self.local.deliver_to_host(room_id, Some(host_id), msg) {
+            self.forward(&Envelope::ToHost {
+                room_id: room_id.to_string(),
+                host_id: Some(host_id.clone()),
+                msg,
+            });
+        }
+    }
+
+    fn register_user(&self, user_id: &UserId, room_id: &str) -> Receiver<ToUserMessage> {
+        let rx = self.local.register_user(user_id, room_id);
+
+        // An older connection of the same user may live on another node
+        self.forward(&Envelope::ToUser {
+            user_id: user_id.clone(),
+            room_id: room_id.to_string(),
+            msg: ToUserMessage::disconnect(user_id.clone(), DisconnectReason::NewConnection),
+        });
+
+        rx
+    }
+
+    fn unregister_user(&self, user_id: &UserId, room_id: &str) {
+        self.local.unregister_user(user_id, room_id);
+    }
+
+    async fn send_to_user(&self, user_id: &UserId, room_id: &str, msg: ToUserMessage) {
+        if let Err(msg) = self.local.deliver_to_user(user_id, room_id, msg) {
+            self.forward(&Envelope::ToUser {
+                user_id: user_id.clone(),
+                room_id: room_id.to_string(),
+                msg,
+            });
+        }
+    }
+
+    fn register_spectator(&self, room_id: &str, user_id: &UserId) -> Receiver<ToUserMessage> {
+        self.local.register_spectator(room_id, user_id)
+    }
+
+    fn unregister_spectator(&self, room_id: &str, user_id: &UserId) {
+        self.local.unregister_spectator(room_id, user_id);
+    }
+
+    /// Spectators watch from every node, so the message is always relayed
+    async fn send_to_spectators(&self, room_id: &str, msg: ToUserMessage) {
+        let envelope = Envelope::ToSpectators {
+            room_id: room_id.to_string(),
+            user_id: None,
+            msg: msg.clone(),
+        };
+        self.local.deliver_to_spectators(room_id, None, msg);
+        self.forward(&envelope);
+    }
+
+    /// The same spectator may watch from several nodes, so this is relayed
+    /// as well
+    async fn send_to_one_spectator(&self, room_id: &str, user_id: &UserId, msg: ToUserMessage) {
+        let envelope = Envelope::ToSpectators {
+            room_id: room_id.to_string(),
+            user_id: Some(user_id.clone()),
+            msg: msg.clone(),
+        };
+        self.local
+            .deliver_to_spectators(room_id, Some(user_id), msg);
+        self.forward(&envelope);
+    }
+
+    /// Deliver to local users directly and relay the rest in one envelope
+    async fn broadcast_to_users(
+        &self,
+        room_id: &str,
+        user_ids: &[UserId],
+        payload: &MessagePayload,
+    ) {
+        let mut remote = Vec::new();
+        for user_id in user_ids {
+            let msg = ToUserMessage::message(user_id.clone(), payload.clone());
+            if self.local.deliver_to_user(user_id, room_id, msg).is_err() {
+                remote.push(user_id.clone());
+            }
+        }
+
+        if !remote.is_empty() {
+            self.forward(&Envelope::Broadcast {
+                room_id: room_id.to_string(),
+                user_ids: remote,
+                payload: payload.clone(),
+            });
+        }
+    }
+}
+
+async fn accept_peers(listener: TcpListener, local: Arc<LocalMessageBus>, auth: MeshAuth) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let local = local.clone();
+                let auth = auth.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    match timeout(HANDSHAKE_TIMEOUT, accept_handshake(&mut stream, &auth)).await {
+                        Ok(Ok(())) => {
+                            tracing::info!("Mesh peer {} connected", addr);
+                            read_peer(stream, local).await;
+                        }
+                        Ok(Err(e)) => {
+                            tracing::warn!("Mesh peer {} failed authentication: {}", addr, e);
+                        }
+                        Err(_) => {
+                            tracing::warn!("Mesh peer {} handshake timed out", addr);
+                        }
+                    }
+                });
+            }
+            Err(e) => {
+                tracing::error!("Mesh accept failed: {}", e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Listener side: answer the connector's nonce, then check its answer to ours.
+async fn accept_handshake(
+    stream: &mut BufReader<TcpStream>,
+    auth: &MeshAuth,
+) -> std::io::Result<()> {
+    let peer_nonce = read_handshake_line(stream).await?;
+    let nonce = Uuid::new_v4().simple().to_string();
+    let reply = format!("{} {}\n", nonce, auth.sign("accept", &peer_nonce));
+    stream.get_mut().write_all(reply.as_bytes()).await?;
+
+    let tag = read_handshake_line(stream).await?;
+    if auth.verify("connect", &nonce, &tag) {
+        Ok(())
+    } else {
+        Err(handshake_error("bad secret"))
+    }
+}
+
+/// Connector side: send a nonce, check the listener's answer, answer its nonce.
+async fn connect_handshake(
+    stream: &mut BufReader<TcpStream>,
+    auth: &MeshAuth,
+) -> std::io::Result<()> {
+    let nonce = Uuid::new_v4().simple().to_string();
+    stream
+        .get_mut()
+        .write_all(format!("{nonce}\n").as_bytes())
+        .await?;
+
+    let line = read_handshake_line(stream).await?;
+    let Some((peer_nonce, tag)) = line.split_once(' ') else {
+        return Err(handshake_error("malformed reply"));
+    };
+    if !auth.verify("accept", &nonce, tag) {
+        return Err(handshake_error("bad secret"));
+    }
+    let reply = format!("{}\n", auth.sign("connect", peer_nonce));
+    stream.get_mut().write_all(reply.as_bytes()).await
+}
+
+/// Read one bounded line, so an unauthenticated peer cannot make us buffer
+/// an endless one.
+async fn read_handshake_line<R: AsyncBufRead + Unpin>(stream: &mut R) -> std::io::Result<String> {
+    let mut line = String::new();
+    stream
+        .take(HANDSHAKE_LINE_LIMIT)
+        .read_line(&mut line)
+        .await?;
+    match line.strip_suffix('\n') {
+        Some(line) => Ok(line.to_string()),
+        None => Err(handshake_error("truncated line")),
+    }
+}
+
+fn handshake_error(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::PermissionDenied, msg)
+}
+
+async fn read_peer(stream: BufReader<TcpStream>, local: Arc<LocalMessageBus>) {
+    let mut lines = stream.lines();
+
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => match serde_json::from_str(&line) {
+                Ok(Envelope::ToHost {
+                    room_id,
+                    host_id,
+                    msg,
+                }) => {
+                    let _ = local.deliver_to_host(&room_id, host_id.as_ref(), msg);
+                }
+                Ok(Envelope::ToUser {
+                    user_id,
+                    room_id,
+                    msg,
+                }) => {
+                    let _ = local.deliver_to_user(&user_id, &room_id, msg);
+                }
+                Ok(Envelope::ToSpectators {
+                    room_id,
+                    user_id,
+                    msg,
+                }) => {
+                    local.deliver_to_spectators(&room_id, user_id.as_ref(), msg);
+                }
+                Ok(Envelope::Broadcast {
+                    room_id,
+                    user_ids,
+                    payload,
+                }) => {
+                    for user_id in user_ids {
+                        let msg = ToUserMessage::message(user_id.clone(), payload.clone());
+                        let _ = local.deliver_to_user(&user_id, &room_id, msg);
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Invalid mesh envelope: {}", e);
+                }
+            },
+            Ok(None) => break,
+            Err(e) => {
+                tracing::warn!("Mesh peer read failed: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+async fn peer_link(addr: String, auth: MeshAuth, mut rx: mpsc::Receiver<Outgoing>) {
+    loop {
+        let stream = match TcpStream::connect(&addr).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::debug!("Mesh peer {} unreachable: {}", addr, e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        let mut stream = BufReader::new(stream);
+        match timeout(HANDSHAKE_TIMEOUT, connect_handshake(&mut stream, &auth)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                tracing::warn!("Mesh peer {} rejected the handshake: {}", addr, e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+            Err(_) => {
+                tracing::warn!("Mesh peer {} handshake timed out", addr);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        }
+        let mut stream = stream.into_inner();
+        let connected_at = Instant::now();
+        tracing::info!("Connected to mesh peer {}", addr);
+
+        loop {
+            let Some(outgoing) = rx.recv().await else {
+                return;
+            };
+            if outgoing
+                .replaces_session_at
+                .is_some_and(|queued_at| queued_at < connected_at)
+            {
+                continue;
+            }
+            if let Err(e) = stream.write_all(outgoing.line.as_bytes()).await {
+                tracing::warn!("Lost connection to mesh peer {}: {}", addr, e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn bind() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        (listener, addr)
+    }
+
+    async fn pair(secret_a: &str, secret_b: &str) -> (MeshMessageBus, MeshMessageBus) {
+        let (listener_a, addr_a) = bind().await;
+        let (listener_b, addr_b) = bind().await;
+        let a = MeshMessageBus::with_listener(
+            listener_a,
+            vec![addr_b],
+            MeshAuth::new(secret_a),
+            OverflowPolicies::default(),
+        );
+        let b = MeshMessageBus::with_listener(
+            listener_b,
+            vec![addr_a],
+            MeshAuth::new(secret_b),
+            OverflowPolicies::default(),
+        );
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn relays_messages_for_remote_users() {
+        let (a, b) = pair("secret", "secret").await;
+        let user = UserId::new("alice");
+        let mut rx = b.register_user(&user, "room");
+
+        let payload = serde_json::json!({ "hello": "world" });
+        a.send_to_user(
+            &user,
+            "room",
+            ToUserMessage::message(user.clone(), payload.clone()),
+        )
+        .await;
+
+        let msg = timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("message was not relayed")
+            .unwrap();
+        assert_eq!(msg.user_id, user);
+        assert_eq!(msg.message, Some(payload));
+    }
+
+    #[tokio::test]
+    async fn relays_host_messages_to_every_node() {
+        let (a, b) = pair("secret", "secret").await;
+        let host = UserId::new("host");
+        let mut local = a.register_host("room", &host);
+        let mut remote = b.register_host("room", &host);
+
+        a.send_to_host("room", ToHostMessage::message(UserId::new("bob"), 1.into()))
+            .await;
+
+        for rx in [&mut local, &mut remote] {
+            let msg = timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .expect("host message was not delivered")
+                .unwrap();
+            assert_eq!(msg.user_id, UserId::new("bob"));
+        }
+    }
+
+    #[tokio::test]
+    async fn session_replacements_do_not_outlive_an_outage() {
+        let (listener_a, addr_a) = bind().await;
+        let (listener_b, addr_b) = bind().await;
+        let auth = MeshAuth::new("secret");
+        let a = MeshMessageBus::with_listener(
+            listener_a,
+            vec![addr_b],
+            auth.clone(),
+            OverflowPolicies::default(),
+        );
+        let [alice, bob] = ["alice", "bob"].map(UserId::new);
+        // Queued while b does not answer yet
+        let _old = a.register_user(&alice, "room");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let b = MeshMessageBus::with_listener(
+            listener_b,
+            vec![addr_a],
+            auth,
+            OverflowPolicies::default(),
+        );
+        let mut fresh = b.register_user(&alice, "room");
+        let mut probe = b.register_user(&bob, "room");
+        a.send_to_user(&bob, "room", ToUserMessage::message(bob.clone(), 1.into()))
+            .await;
+
+        timeout(Duration::from_secs(5), probe.recv())
+            .await
+            .expect("the link did not come up")
+            .unwrap();
+        assert!(fresh.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn drops_peers_with_another_secret() {
+        let (a, b) = pair("secret", "guess").await;
+        let user = UserId::new("alice");
+        let mut rx = b.register_user(&user, "room");
+
+        a.send_to_user(
+            &user,
+            "room",
+            ToUserMessage::message(user.clone(), 1.into()),
+        )
+        .await;
+
+        assert!(
+            timeout(Duration::from_millis(500), rx.recv())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn handshake_rejects_a_forged_tag() {
+        let auth = MeshAuth::new("secret");
+        let tag = auth.sign("accept", "nonce");
+        assert!(auth.verify("accept", "nonce", &tag));
+        assert!(!auth.verify("connect", "nonce", &tag));
+        assert!(!auth.verify("accept", "other", &tag));
+        assert!(!MeshAuth::new("guess").verify("accept", "nonce", &tag));
+        assert!(!auth.verify("accept", "nonce", "not hex"));
+    }
+}
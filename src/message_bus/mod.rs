@@ -1,82 +1,77 @@
-use dashmap::DashMap;
-use tokio::sync::mpsc;
+mod channel;
+mod local;
+mod mesh;
+
+pub use channel::Receiver;
+pub use local::LocalMessageBus;
+pub use mesh::{MeshAuth, MeshMessageBus};
+
+use std::time::Duration;
+
+use async_trait::async_trait;
 
 use crate::domain::{
     event::DisconnectReason,
-    message::{ToHostMessage, ToUserMessage},
+    message::{MessagePayload, ToHostMessage, ToUserMessage},
     user::UserId,
 };
 
-const CHANNEL_BUFFER: usize = 256;
+/// Routes messages between host and user sockets.
+///
+/// Host and spectator channels are keyed by room and user, user channels by
+/// user and room.
+#[async_trait]
+pub trait Bus: Send + Sync {
+    /// Register a host channel. An older connection of the same host in the
+    /// room loses its channel.
+    fn register_host(&self, room_id: &str, host_id: &UserId) -> Receiver<ToHostMessage>;
 
-#[derive(Clone)]
-pub struct MessageBus {
-    /// roomId (string) -> sender for messages to host
-    host_channels: DashMap<String, mpsc::Sender<ToHostMessage>>,
-    /// "userId:roomId" -> sender for messages to user
-    user_channels: DashMap<String, mpsc::Sender<ToUserMessage>>,
-}
+    fn unregister_host(&self, room_id: &str, host_id: &UserId);
 
-impl Default for MessageBus {
-    fn default() -> Self {
-        Self::new()
-    }
-}
+    /// Send a copy of `msg` to every host connected to the room
+    async fn send_to_host(&self, room_id: &str, msg: ToHostMessage);
 
-impl MessageBus {
-    pub fn new() -> Self {
-        Self {
-            host_channels: DashMap::new(),
-            user_channels: DashMap::new(),
-        }
-    }
+    async fn send_to_one_host(&self, room_id: &str, host_id: &UserId, msg: ToHostMessage);
 
-    pub fn register_host(&self, room_id: &str) -> mpsc::Receiver<ToHostMessage> {
-        let (tx, rx) = mpsc::channel(CHANNEL_BUFFER);
-        self.host_channels.insert(room_id.to_string(), tx);
-        rx
-    }
+    /// Register a user channel. If the user already has a connection,
+    /// it receives a Disconnect(NewConnection).
+    fn register_user(&self, user_id: &UserId, room_id: &str) -> Receiver<ToUserMessage>;
 
-    pub fn unregister_host(&self, room_id: &str) {
-        self.host_channels.remove(room_id);
-    }
+    fn unregister_user(&self, user_id: &UserId, room_id: &str);
 
-    pub fn send_to_host(&self, room_id: &str, msg: ToHostMessage) {
-        if let Some(tx) = self.host_channels.get(room_id) {
-            let _ = tx.try_send(msg);
-        }
-    }
+    async fn send_to_user(&self, user_id: &UserId, room_id: &str, msg: ToUserMessage);
 
-    /// Register a user channel. If the user already has a connection,
-    /// send a Disconnect(NewConnection) to the old channel first.
-    pub fn register_user(&self, user_id: &UserId, room_id: &str) -> mpsc::Receiver<ToUserMessage> {
-        let key = user_channel_key(user_id, room_id);
-        let (tx, rx) = mpsc::channel(CHANNEL_BUFFER);
-
-        if let Some(old_tx) = self.user_channels.insert(key, tx) {
-            let _ = old_tx.try_send(ToUserMessage::disconnect(
-                user_id.clone(),
-                DisconnectReason::NewConnection,
-            ));
-        }
+    /// Register a spectator channel. An older connection of the same
+    /// spectator in the room loses its channel.
+    fn register_spectator(&self, room_id: &str, user_id: &UserId) -> Receiver<ToUserMessage>;
 
-        rx
-    }
+    fn unregister_spectator(&self, room_id: &str, user_id: &UserId);
 
-    pub fn unregister_user(&self, user_id: &UserId, room_id: &str) {
-        let key = user_channel_key(user_id, room_id);
-        self.user_channels.remove(&key);
-    }
+    /// Send a copy of `msg` to every spectator of the room, each with its
+    /// `user_id` set to the spectator's
+    async fn send_to_spectators(&self, room_id: &str, msg: ToUserMessage);
+
+    async fn send_to_one_spectator(&self, room_id: &str, user_id: &UserId, msg: ToUserMessage);
 
-    pub fn send_to_user(&self, user_id: &UserId, room_id: &str, msg: ToUserMessage) {
-        let key = user_channel_key(user_id, room_id);
-        if let Some(tx) = self.user_channels.get(&key) {
-            let _ = tx.try_send(msg);
+    /// Send a copy of `payload` to each of the given users
+    async fn broadcast_to_users(
+        &self,
+        room_id: &str,
+        user_ids: &[UserId],
+        payload: &MessagePayload,
+    ) {
+        for user_id in user_ids {
+            self.send_to_user(
+                user_id,
+                room_id,
+                ToUserMessage::message(user_id.clone(), payload.clone()),
+            )
+            .await;
         }
     }
 
     /// Disconnect all users in a room by sending Disconnect messages
-    pub fn disconnect_room_users(
+    async fn disconnect_room_users(
         &self,
         room_id: &str,
         user_ids: &[UserId],
@@ -87,16 +82,72 @@ impl MessageBus {
                 user_id,
                 room_id,
                 ToUserMessage::disconnect(user_id.clone(), reason.clone()),
-            );
+            )
+            .await;
         }
     }
 
-    /// Disconnect the host of a room
-    pub fn disconnect_host(&self, room_id: &str, host_id: &UserId, reason: DisconnectReason) {
-        self.send_to_host(room_id, ToHostMessage::disconnect(host_id.clone(), reason));
+    /// Disconnect one host of a room
+    async fn disconnect_host(&self, room_id: &str, host_id: &UserId, reason: DisconnectReason) {
+        self.send_to_one_host(
+            room_id,
+            host_id,
+            ToHostMessage::disconnect(host_id.clone(), reason),
+        )
+        .await;
+    }
+
+    /// Disconnect one spectator of a room
+    async fn disconnect_spectator(
+        &self,
+        room_id: &str,
+        user_id: &UserId,
+        reason: DisconnectReason,
+    ) {
+        self.send_to_one_spectator(
+            room_id,
+            user_id,
+            ToUserMessage::disconnect(user_id.clone(), reason),
+        )
+        .await;
     }
 }
 
-fn user_channel_key(user_id: &UserId, room_id: &str) -> String {
-    format!("{}:{}", user_id.as_str(), room_id)
+/// What happens to a message whose recipient queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropNewest,
+    DropOldest,
+    /// Cut the recipient off with `DisconnectReason::SlowConsumer`
+    Disconnect,
+    /// Hold the message back until the queue has room, dropping it after
+    /// the timeout. The sender itself never waits.
+    AwaitTimeout(Duration),
+}
+
+impl OverflowPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            OverflowPolicy::DropNewest => "DropNewest",
+            OverflowPolicy::DropOldest => "DropOldest",
+            OverflowPolicy::Disconnect => "Disconnect",
+            OverflowPolicy::AwaitTimeout(_) => "AwaitTimeout",
+        }
+    }
+}
+
+/// Overflow policy for each direction of the bus
+#[derive(Debug, Clone, Copy)]
+pub struct OverflowPolicies {
+    pub to_host: OverflowPolicy,
+    pub to_user: OverflowPolicy,
+}
+
+impl Default for OverflowPolicies {
+    fn default() -> Self {
+        Self {
+            to_host: OverflowPolicy::DropNewest,
+            to_user: OverflowPolicy::DropNewest,
+        }
+    }
 }
@@ -0,0 +1,166 @@
+use std::sync::LazyLock;
+
+use prometheus::{Encoder, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+
+use crate::domain::event::DisconnectReason;
+
+/// Process-wide metrics, exposed in Prometheus text format on `/metrics`.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// Filled from storage on every scrape
+    rooms: IntGaugeVec,
+    pub connected_hosts: IntGauge,
+    pub connected_users: IntGauge,
+    pub connected_spectators: IntGauge,
+    /// Labelled by `direction`: `to_host`, `to_user`
+    pub messages_routed: IntCounterVec,
+    /// Labelled by `direction`: `to_host`, `to_user`, `peer`
+    pub messages_dropped: IntCounterVec,
+    disconnects: IntCounterVec,
+    pong_timeouts: IntCounterVec,
+    /// Labelled by the `limit` that was exceeded: `size`, `depth`
+    oversized_messages: IntCounterVec,
+    upgrade_rejections: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("rooms".into()), None).expect("valid metrics prefix");
+
+        let rooms = IntGaugeVec::new(
+            Opts::new("active", "Rooms in storage by type"),
+            &["room_type"],
+        )
+        .unwrap();
+        let connected_hosts =
+            IntGauge::new("connected_hosts", "Host WebSocket connections").unwrap();
+        let connected_users =
+            IntGauge::new("connected_users", "User WebSocket connections").unwrap();
+        let connected_spectators =
+            IntGauge::new("connected_spectators", "Spectator WebSocket connections").unwrap();
+        let messages_routed = IntCounterVec::new(
+            Opts::new(
+                "messages_routed_total",
+                "Messages delivered to a local channel",
+            ),
+            &["direction"],
+        )
+        .unwrap();
+        let messages_dropped = IntCounterVec::new(
+            Opts::new(
+                "messages_dropped_total",
+                "Messages dropped because a channel was full or closed",
+            ),
+            &["direction"],
+        )
+        .unwrap();
+        let disconnects = IntCounterVec::new(
+            Opts::new("disconnects_total", "Connections ended with a reason"),
+            &["role", "reason"],
+        )
+        .unwrap();
+        let pong_timeouts = IntCounterVec::new(
+            Opts::new("pong_timeouts_total", "Connections that missed a pong"),
+            &["role"],
+        )
+        .unwrap();
+        let oversized_messages = IntCounterVec::new(
+            Opts::new(
+                "oversized_messages_total",
+                "Senders disconnected for exceeding a message limit",
+            ),
+            &["role", "limit"],
+        )
+        .unwrap();
+        let upgrade_rejections = IntCounterVec::new(
+            Opts::new(
+                "upgrade_rejections_total",
+                "WebSocket upgrades refused by status",
+            ),
+            &["status"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(rooms.clone())).unwrap();
+        registry
+            .register(Box::new(connected_hosts.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connected_users.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connected_spectators.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(messages_routed.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(messages_dropped.clone()))
+            .unwrap();
+        registry.register(Box::new(disconnects.clone())).unwrap();
+        registry.register(Box::new(pong_timeouts.clone())).unwrap();
+        registry
+            .register(Box::new(oversized_messages.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upgrade_rejections.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            rooms,
+            connected_hosts,
+            connected_users,
+            connected_spectators,
+            messages_routed,
+            messages_dropped,
+            disconnects,
+            pong_timeouts,
+            oversized_messages,
+            upgrade_rejections,
+        }
+    }
+
+    pub fn record_disconnect(&self, role: &str, reason: &DisconnectReason) {
+        self.disconnects
+            .with_label_values(&[role, &format!("{reason:?}")])
+            .inc();
+    }
+
+    pub fn record_pong_timeout(&self, role: &str) {
+        self.pong_timeouts.with_label_values(&[role]).inc();
+        self.record_disconnect(role, &DisconnectReason::PingPong);
+    }
+
+    pub fn record_oversized(&self, role: &str, limit: &str) {
+        self.oversized_messages
+            .with_label_values(&[role, limit])
+            .inc();
+        self.record_disconnect(role, &DisconnectReason::MessageTooLarge);
+    }
+
+    pub fn record_upgrade_rejection(&self, status: u16) {
+        self.upgrade_rejections
+            .with_label_values(&[status.to_string().as_str()])
+            .inc();
+    }
+
+    /// Encode all metrics, taking room counts from the given snapshot
+    pub fn render(&self, rooms_by_type: Vec<(String, usize)>) -> String {
+        self.rooms.reset();
+        for (room_type, count) in rooms_by_type {
+            self.rooms
+                .with_label_values(&[room_type.as_str()])
+                .set(count as i64);
+        }
+
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::{HashMap, VecDeque};
+
+use dashmap::DashMap;
+
+use super::{CreateRoomError, HostPresence, JoinOutcome, LeaveOutcome, Member, RoomStore};
+use crate::domain::{
+    history::{HistoryEntry, MessageHistory},
+    message::MessagePayload,
+    room::{Room, RoomId, RoomState, unix_now_millis},
+    user::UserId,
+};
+
+#[derive(Clone, Copy)]
+struct Admission {
+    /// Unix time in milliseconds
+    joined_at: u64,
+    history_seen: u64,
+    can_host: bool,
+}
+
+#[derive(Clone, Default)]
+struct RoomMembers {
+    users: HashMap<UserId, Admission>,
+    /// Queued users and whether they can host
+    waitlist: VecDeque<(UserId, bool)>,
+}
+
+/// Default storage: everything lives in process memory and is lost on restart.
+#[derive(Clone)]
+pub struct InMemoryRoomStorage {
+    rooms: DashMap<String, Room>,
+    room_users: DashMap<String, RoomMembers>,
+    /// Broadcast history of the rooms that keep one. Locked after the
+    /// room's members, so recording and admission each see one state.
+    histories: DashMap<String, MessageHistory>,
+    hosts: DashMap<String, HashMap<UserId, HostPresence>>,
+    /// Open sockets of each spectator
+    spectators: DashMap<String, HashMap<UserId, usize>>,
+}
+
+impl Default for InMemoryRoomStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryRoomStorage {
+    pub fn new() -> Self {
+        Self {
+            rooms: DashMap::new(),
+            room_users: DashMap::new(),
+            histories: DashMap::new(),
+            hosts: DashMap::new(),
+            spectators: DashMap::new(),
+        }
+    }
+
+    /// Id of the latest broadcast recorded in a room, 0 for none
+    fn last_history_id(&self, room_id: &str) -> u64 {
+        self.histories
+            .get(room_id)
+            .map_or(0, |history| history.last_id())
+    }
+
+    fn admission(&self, room_id: &str, can_host: bool) -> Admission {
+        Admission {
+            joined_at: unix_now_millis(),
+            history_seen: self.last_history_id(room_id),
+            can_host,
+        }
+    }
+}
+
+impl RoomStore for InMemoryRoomStorage {
+    fn create_room(&self, room: Room) -> Result<RoomId, CreateRoomError> {
+        let key = room.id.to_string();
+        if self.rooms.contains_key(&key) {
+            return Err(CreateRoomError::RoomAlreadyExists);
+        }
+        let room_id = room.id.clone();
+        if let Some(limits) = room.history {
+            self.histories
+                .insert(key.clone(), MessageHistory::new(limits));
+        }
+        self.rooms.insert(key.clone(), room);
+        self.room_users.insert(key, RoomMembers::default());
+        Ok(room_id)
+    }
+
+    fn get_room(&self, room_id: &str) -> Option<Room> {
+        self.rooms.get(room_id).map(|r| r.clone())
+    }
+
+    fn remove_room(&self, room_id: &str) -> Option<Room> {
+        let room = self.rooms.remove(room_id).map(|(_, r)| r);
+        self.room_users.remove(room_id);
+        self.histories.remove(room_id);
+        self.hosts.remove(room_id);
+        self.spectators.remove(room_id);
+        room
+    }
+
+    fn update_room(&self, room_id: &str, update: &mut dyn FnMut(&mut Room)) -> Option<Room> {
+        let mut room = self.rooms.get_mut(room_id)?;
+        update(&mut room);
+        Some(room.clone())
+    }
+
+    fn get_rooms_paginated(&self, page: usize, size: usize) -> (Vec<Room>, usize) {
+        let all: Vec<Room> = self.rooms.iter().map(|r| r.value().clone()).collect();
+        let total = all.len();
+        let start = page * size;
+        let rooms = all.into_iter().skip(start).take(size).collect();
+        (rooms, total)
+    }
+
+    fn count_rooms_by_type(&self) -> Vec<(String, usize)> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for room in self.rooms.iter() {
+            *counts
+                .entry(room.room_type.as_str().to_string())
+                .or_default() += 1;
+        }
+        counts.into_iter().collect()
+    }
+
+    fn add_user_to_room(&self, room_id: &str, user_id: UserId, can_host: bool) -> JoinOutcome {
+        let Some(room) = self.get_room(room_id) else {
+            return JoinOutcome::RoomNotFound;
+        };
+        let Some(mut members) = self.room_users.get_mut(room_id) else {
+            return JoinOutcome::RoomNotFound;
+        };
+
+        if members.users.contains_key(&user_id) {
+            return JoinOutcome::AlreadyMember;
+        }
+        if let Some(index) = members.waitlist.iter().position(|(u, _)| *u == user_id) {
+            return JoinOutcome::Waitlisted(index + 1);
+        }
+        if !room.is_full(members.users.len()) {
+            members
+                .users
+                .insert(user_id, self.admission(room_id, can_host));
+            return JoinOutcome::Joined;
+        }
+        if room.waitlist {
+            members.waitlist.push_back((user_id, can_host));
+            return JoinOutcome::Waitlisted(members.waitlist.len());
+        }
+        JoinOutcome::Full
+    }
+
+    fn remove_user_from_room(&self, room_id: &str, user_id: &UserId) -> LeaveOutcome {
+        let Some(room) = self.get_room(room_id) else {
+            return LeaveOutcome::NotInRoom;
+        };
+        let Some(mut members) = self.room_users.get_mut(room_id) else {
+            return LeaveOutcome::NotInRoom;
+        };
+
+        if members.users.remove(user_id).is_none() {
+            let queued = members.waitlist.len();
+            members.waitlist.retain(|(u, _)| u != user_id);
+            return if members.waitlist.len() < queued {
+                LeaveOutcome::LeftWaitlist
+            } else {
+                LeaveOutcome::NotInRoom
+            };
+        }
+        let promoted = promote_one(&room, &mut members, |can_host| {
+            self.admission(room_id, can_host)
+        });
+        LeaveOutcome::Left { promoted }
+    }
+
+    fn promote_waitlisted(&self, room_id: &str) -> Vec<UserId> {
+        let (Some(room), Some(mut members)) =
+            (self.get_room(room_id), self.room_users.get_mut(room_id))
+        else {
+            return Vec::new();
+        };
+        std::iter::from_fn(|| {
+            promote_one(&room, &mut members, |can_host| {
+                self.admission(room_id, can_host)
+            })
+        })
+        .collect()
+    }
+
+    fn is_user_in_room(&self, room_id: &str, user_id: &UserId) -> bool {
+        self.room_users
+            .get(room_id)
+            .is_some_and(|members| members.users.contains_key(user_id))
+    }
+
+    fn get_room_user_count(&self, room_id: &str) -> usize {
+        self.room_users
+            .get(room_id)
+            .map(|members| members.users.len())
+            .unwrap_or(0)
+    }
+
+    fn get_room_users(&self, room_id: &str) -> Vec<UserId> {
+        self.room_users
+            .get(room_id)
+            .map(|members| members.users.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn get_room_members(&self, room_id: &str) -> Vec<Member> {
+        self.room_users
+            .get(room_id)
+            .map(|members| {
+                members
+                    .users
+                    .iter()
+                    .map(|(user_id, admission)| member(user_id, admission))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn get_room_member(&self, room_id: &str, user_id: &UserId) -> Option<Member> {
+        let members = self.room_users.get(room_id)?;
+        members
+            .users
+            .get(user_id)
+            .map(|admission| member(user_id, admission))
+    }
+
+    fn get_waitlist_count(&self, room_id: &str) -> usize {
+        self.room_users
+            .get(room_id)
+            .map(|members| members.waitlist.len())
+            .unwrap_or(0)
+    }
+
+    fn clear_room_users(&self, room_id: &str) -> Vec<UserId> {
+        if let Some(mut members) = self.room_users.get_mut(room_id) {
+            let RoomMembers { users, waitlist } = std::mem::take(&mut *members);
+            users
+                .into_keys()
+                .chain(waitlist.into_iter().map(|(user_id, _)| user_id))
+                .collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn record_broadcast(&self, room_id: &str, message: MessagePayload) -> Vec<UserId> {
+        let Some(members) = self.room_users.get(room_id) else {
+            return Vec::new();
+        };
+        if let Some(mut history) = self.histories.get_mut(room_id) {
+            history.push(message, unix_now_millis());
+        }
+        members.users.keys().cloned().collect()
+    }
+
+    fn get_history(&self, room_id: &str, up_to: Option<u64>) -> Option<Vec<HistoryEntry>> {
+        let history = self.histories.get(room_id)?;
+        let entries = history
+            .recent(unix_now_millis(), up_to.unwrap_or(u64::MAX))
+            .cloned()
+            .collect();
+        Some(entries)
+    }
+
+    fn set_host_presence(&self, room_id: &str, host_id: &UserId, presence: Option<HostPresence>) {
+        match presence {
+            // A removed room takes no hosts
+            Some(presence) if self.rooms.contains_key(room_id) => {
+                self.hosts
+                    .entry(room_id.to_string())
+                    .or_default()
+                    .insert(host_id.clone(), presence);
+            }
+            Some(_) => {}
+            None => {
+                self.hosts.remove_if_mut(room_id, |_, hosts| {
+                    hosts.remove(host_id);
+                    hosts.is_empty()
+                });
+            }
+        }
+    }
+
+    fn get_host_presence(&self, room_id: &str) -> Vec<(UserId, HostPresence)> {
+        self.hosts
+            .get(room_id)
+            .map(|hosts| {
+                hosts
+                    .iter()
+                    .map(|(host_id, presence)| (host_id.clone(), *presence))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn add_spectator(&self, room_id: &str, user_id: &UserId) -> bool {
+        if !self.rooms.contains_key(room_id) {
+            return false;
+        }
+        *self
+            .spectators
+            .entry(room_id.to_string())
+            .or_default()
+            .entry(user_id.clone())
+            .or_default() += 1;
+        true
+    }
+
+    fn remove_spectator(&self, room_id: &str, user_id: &UserId) {
+        self.spectators.remove_if_mut(room_id, |_, spectators| {
+            if let Some(sockets) = spectators.get_mut(user_id) {
+                *sockets -= 1;
+                if *sockets == 0 {
+                    spectators.remove(user_id);
+                }
+            }
+            spectators.is_empty()
+        });
+    }
+
+    fn get_spectator_count(&self, room_id: &str) -> usize {
+        self.spectators
+            .get(room_id)
+            .map_or(0, |spectators| spectators.values().sum())
+    }
+}
+
+fn member(user_id: &UserId, admission: &Admission) -> Member {
+    Member {
+        user_id: user_id.clone(),
+        joined_at: admission.joined_at,
+        history_seen: admission.history_seen,
+        can_host: admission.can_host,
+    }
+}
+
+/// Move the first waitlisted user into a free slot of an open room
+fn promote_one(
+    room: &Room,
+    members: &mut RoomMembers,
+    admission: impl FnOnce(bool) -> Admission,
+) -> Option<UserId> {
+    if room.state != RoomState::Open || room.is_full(members.users.len()) {
+        return None;
+    }
+    let (promoted, can_host) = members.waitlist.pop_front()?;
+    members.users.insert(promoted.clone(), admission(can_host));
+    Some(promoted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{history::HistoryLimits, room::RoomType};
+
+    fn storage_with_room(max_users: usize) -> (InMemoryRoomStorage, String) {
+        let storage = InMemoryRoomStorage::new();
+        let room = Room::new(UserId::new("host"), RoomType::new("game"))
+            .with_capacity(Some(max_users), true);
+        let room_id = storage.create_room(room).unwrap().to_string();
+        (storage, room_id)
+    }
+
+    #[test]
+    fn promotes_the_first_waitlisted_user() {
+        let (storage, room_id) = storage_with_room(1);
+        let [alice, bob, carol] = ["alice", "bob", "carol"].map(UserId::new);
+        assert_eq!(
+            storage.add_user_to_room(&room_id, alice.clone(), false),
+            JoinOutcome::Joined
+        );
+        assert_eq!(
+            storage.add_user_to_room(&room_id, bob.clone(), false),
+            JoinOutcome::Waitlisted(1)
+        );
+        assert_eq!(
+            storage.add_user_to_room(&room_id, carol, false),
+            JoinOutcome::Waitlisted(2)
+        );
+
+        assert_eq!(
+            storage.remove_user_from_room(&room_id, &alice),
+            LeaveOutcome::Left {
+                promoted: Some(bob.clone())
+            }
+        );
+        assert!(storage.is_user_in_room(&room_id, &bob));
+        assert_eq!(storage.get_waitlist_count(&room_id), 1);
+    }
+
+    #[test]
+    fn leaving_the_waitlist_is_not_leaving_the_room() {
+        let (storage, room_id) = storage_with_room(1);
+        let [alice, bob] = ["alice", "bob"].map(UserId::new);
+        storage.add_user_to_room(&room_id, alice, false);
+        storage.add_user_to_room(&room_id, bob.clone(), false);
+
+        assert_eq!(
+            storage.remove_user_from_room(&room_id, &bob),
+            LeaveOutcome::LeftWaitlist
+        );
+        assert_eq!(
+            storage.remove_user_from_room(&room_id, &bob),
+            LeaveOutcome::NotInRoom
+        );
+    }
+
+    #[test]
+    fn keeps_the_waitlist_until_the_room_reopens() {
+        let (storage, room_id) = storage_with_room(1);
+        let [alice, bob] = ["alice", "bob"].map(UserId::new);
+        storage.add_user_to_room(&room_id, alice.clone(), false);
+        storage.add_user_to_room(&room_id, bob.clone(), false);
+        storage.update_room(&room_id, &mut |room| room.state = RoomState::Locked);
+
+        assert_eq!(
+            storage.remove_user_from_room(&room_id, &alice),
+            LeaveOutcome::Left { promoted: None }
+        );
+        assert!(storage.promote_waitlisted(&room_id).is_empty());
+
+        storage.update_room(&room_id, &mut |room| room.state = RoomState::Open);
+        assert_eq!(storage.promote_waitlisted(&room_id), vec![bob]);
+    }
+
+    #[test]
+    fn replays_only_what_was_recorded_before_admission() {
+        let storage = InMemoryRoomStorage::new();
+        let room = Room::new(UserId::new("host"), RoomType::new("game")).with_history(Some(
+            HistoryLimits {
+                max_messages: 3,
+                max_age_secs: None,
+            },
+        ));
+        let room_id = storage.create_room(room).unwrap().to_string();
+        let [alice, bob] = ["alice", "bob"].map(UserId::new);
+
+        storage.add_user_to_room(&room_id, alice.clone(), false);
+        for n in 1..=3 {
+            assert_eq!(
+                storage.record_broadcast(&room_id, serde_json::json!(n)),
+                vec![alice.clone()]
+            );
+        }
+        storage.add_user_to_room(&room_id, bob.clone(), false);
+        storage.record_broadcast(&room_id, serde_json::json!(4));
+
+        let seen = storage
+            .get_room_member(&room_id, &bob)
+            .unwrap()
+            .history_seen;
+        let replayed: Vec<u64> = storage
+            .get_history(&room_id, Some(seen))
+            .unwrap()
+            .iter()
+            .map(|entry| entry.id)
+            .collect();
+        // The oldest fell out of the ring, the newest reaches bob live
+        assert_eq!(replayed, vec![2, 3]);
+    }
+}
@@ -1,107 +1,133 @@
-use std::collections::HashSet;
+mod memory;
+mod sqlite;
 
-use dashmap::DashMap;
+pub use memory::InMemoryRoomStorage;
+pub use sqlite::SqliteRoomStorage;
 
 use crate::domain::{
+    history::HistoryEntry,
+    message::MessagePayload,
     room::{Room, RoomId},
     user::UserId,
 };
 
-#[derive(Clone)]
-pub struct RoomStorage {
-    rooms: DashMap<String, Room>,
-    room_users: DashMap<String, HashSet<UserId>>,
+/// Backend-agnostic room storage.
+///
+/// Rooms are addressed by their string id, membership by `UserId`.
+/// Implementations must be safe to share between request handlers and
+/// WebSocket tasks.
+pub trait RoomStore: Send + Sync {
+    fn create_room(&self, room: Room) -> Result<RoomId, CreateRoomError>;
+
+    fn get_room(&self, room_id: &str) -> Option<Room>;
+
+    fn remove_room(&self, room_id: &str) -> Option<Room>;
+
+    /// Apply `update` to a stored room atomically. Returns the updated room,
+    /// or `None` if the room does not exist.
+    fn update_room(&self, room_id: &str, update: &mut dyn FnMut(&mut Room)) -> Option<Room>;
+
+    fn get_rooms_paginated(&self, page: usize, size: usize) -> (Vec<Room>, usize);
+
+    /// Number of stored rooms per room type
+    fn count_rooms_by_type(&self) -> Vec<(String, usize)>;
+
+    /// Add a member, or queue them when the room is full and has a waitlist.
+    /// `can_host` records whether their token carries the Host role.
+    fn add_user_to_room(&self, room_id: &str, user_id: UserId, can_host: bool) -> JoinOutcome;
+
+    /// Remove a member or waitlisted user, promoting the first waitlisted
+    /// user into a freed slot while the room is open.
+    fn remove_user_from_room(&self, room_id: &str, user_id: &UserId) -> LeaveOutcome;
+
+    /// Admit waitlisted users into free slots while the room is open, e.g.
+    /// after it was unlocked. Returns them in admission order.
+    fn promote_waitlisted(&self, room_id: &str) -> Vec<UserId>;
+
+    fn is_user_in_room(&self, room_id: &str, user_id: &UserId) -> bool;
+
+    fn get_room_user_count(&self, room_id: &str) -> usize;
+
+    fn get_room_users(&self, room_id: &str) -> Vec<UserId>;
+
+    /// Members with the time they were admitted, waitlisted users excluded
+    fn get_room_members(&self, room_id: &str) -> Vec<Member>;
+
+    fn get_room_member(&self, room_id: &str, user_id: &UserId) -> Option<Member>;
+
+    fn get_waitlist_count(&self, room_id: &str) -> usize;
+
+    /// Remove all members and waitlisted users, returning them.
+    fn clear_room_users(&self, room_id: &str) -> Vec<UserId>;
+
+    /// Keep a message the host broadcast to the whole room, if the room
+    /// keeps history. Returns the members to deliver it to, read in the
+    /// same step: a user admitted later finds it in the history instead.
+    fn record_broadcast(&self, room_id: &str, message: MessagePayload) -> Vec<UserId>;
+
+    /// Recorded broadcasts still within the age limit, oldest first, up to
+    /// and including id `up_to`. `None` if the room keeps no history.
+    fn get_history(&self, room_id: &str, up_to: Option<u64>) -> Option<Vec<HistoryEntry>>;
+
+    /// Record that a host is connected to this node, parked on it, or gone
+    /// (`None`). A connected host belongs to the node that set it last;
+    /// other nodes can no longer park or forget it.
+    fn set_host_presence(&self, room_id: &str, host_id: &UserId, presence: Option<HostPresence>);
+
+    /// Hosts of the room that are connected or parked on any node
+    fn get_host_presence(&self, room_id: &str) -> Vec<(UserId, HostPresence)>;
+
+    /// Count a spectator socket opened on this node. Returns false if the
+    /// room does not exist.
+    fn add_spectator(&self, room_id: &str, user_id: &UserId) -> bool;
+
+    fn remove_spectator(&self, room_id: &str, user_id: &UserId);
+
+    /// Open spectator sockets of the room across all nodes
+    fn get_spectator_count(&self, room_id: &str) -> usize;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostPresence {
+    Connected,
+    /// Dropped, within the reconnect grace period
+    Away,
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub user_id: UserId,
+    /// Unix time in milliseconds the user joined or left the waitlist
+    pub joined_at: u64,
+    /// Id of the last broadcast recorded before the user was admitted
+    pub history_seen: u64,
+    /// The user's token carries the Host role
+    pub can_host: bool,
 }
 
-impl Default for RoomStorage {
-    fn default() -> Self {
-        Self::new()
-    }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinOutcome {
+    Joined,
+    AlreadyMember,
+    /// Queued at the given 1-based position
+    Waitlisted(usize),
+    Full,
+    RoomNotFound,
 }
 
-impl RoomStorage {
-    pub fn new() -> Self {
-        Self {
-            rooms: DashMap::new(),
-            room_users: DashMap::new(),
-        }
-    }
-
-    pub fn create_room(&self, room: Room) -> Result<RoomId, CreateRoomError> {
-        let key = room.id.to_string();
-        if self.rooms.contains_key(&key) {
-            return Err(CreateRoomError::RoomAlreadyExists);
-        }
-        let room_id = room.id.clone();
-        self.rooms.insert(key.clone(), room);
-        self.room_users.insert(key, HashSet::new());
-        Ok(room_id)
-    }
-
-    pub fn get_room(&self, room_id: &str) -> Option<Room> {
-        self.rooms.get(room_id).map(|r| r.clone())
-    }
-
-    pub fn remove_room(&self, room_id: &str) -> Option<Room> {
-        let room = self.rooms.remove(room_id).map(|(_, r)| r);
-        self.room_users.remove(room_id);
-        room
-    }
-
-    pub fn get_rooms_paginated(&self, page: usize, size: usize) -> (Vec<Room>, usize) {
-        let all: Vec<Room> = self.rooms.iter().map(|r| r.value().clone()).collect();
-        let total = all.len();
-        let start = page * size;
-        let rooms = all.into_iter().skip(start).take(size).collect();
-        (rooms, total)
-    }
-
-    pub fn add_user_to_room(&self, room_id: &str, user_id: UserId) -> bool {
-        if let Some(mut users) = self.room_users.get_mut(room_id) {
-            users.insert(user_id)
-        } else {
-            false
-        }
-    }
-
-    pub fn remove_user_from_room(&self, room_id: &str, user_id: &UserId) {
-        if let Some(mut users) = self.room_users.get_mut(room_id) {
-            users.remove(user_id);
-        }
-    }
-
-    pub fn is_user_in_room(&self, room_id: &str, user_id: &UserId) -> bool {
-        self.room_users
-            .get(room_id)
-            .is_some_and(|users| users.contains(user_id))
-    }
-
-    pub fn get_room_user_count(&self, room_id: &str) -> usize {
-        self.room_users
-            .get(room_id)
-            .map(|users| users.len())
-            .unwrap_or(0)
-    }
-
-    pub fn get_room_users(&self, room_id: &str) -> Vec<UserId> {
-        self.room_users
-            .get(room_id)
-            .map(|users| users.iter().cloned().collect())
-            .unwrap_or_default()
-    }
-
-    pub fn clear_room_users(&self, room_id: &str) -> Vec<UserId> {
-        if let Some(mut users) = self.room_users.get_mut(room_id) {
-            let result: Vec<UserId> = users.iter().cloned().collect();
-            users.clear();
-            result
-        } else {
-            Vec::new()
-        }
-    }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LeaveOutcome {
+    /// A member left, and the waitlisted user who took the slot, if any
+    Left {
+        promoted: Option<UserId>,
+    },
+    /// The user was still waitlisted and never joined the room
+    LeftWaitlist,
+    NotInRoom,
 }
 
 #[derive(Debug)]
 pub enum CreateRoomError {
     RoomAlreadyExists,
+    Backend(String),
 }
@@ -0,0 +1,959 @@
+use std::sync::{Mutex, MutexGuard};
+
+use rusqlite::{Connection, OptionalExtension, Transaction, TransactionBehavior, params};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+use super::{CreateRoomError, HostPresence, JoinOutcome, LeaveOutcome, Member, RoomStore};
+use crate::domain::{
+    history::HistoryEntry,
+    message::MessagePayload,
+    room::{Room, RoomId, RoomState, unix_now_millis},
+    user::UserId,
+};
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS rooms (
+        id   TEXT PRIMARY KEY NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS room_users (
+        room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+        user_id TEXT NOT NULL,
+        node    TEXT NOT NULL,
+        joined_at INTEGER NOT NULL DEFAULT 0,
+        history_seen INTEGER NOT NULL DEFAULT 0,
+        can_host INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (room_id, user_id)
+    );
+    CREATE TABLE IF NOT EXISTS room_waitlist (
+        seq     INTEGER PRIMARY KEY AUTOINCREMENT,
+        room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+        user_id TEXT NOT NULL,
+        node    TEXT NOT NULL,
+        can_host INTEGER NOT NULL DEFAULT 0,
+        UNIQUE (room_id, user_id)
+    );
+    CREATE TABLE IF NOT EXISTS room_history (
+        room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+        id      INTEGER NOT NULL,
+        sent_at INTEGER NOT NULL,
+        message TEXT NOT NULL,
+        PRIMARY KEY (room_id, id)
+    );
+    CREATE TABLE IF NOT EXISTS room_hosts (
+        room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+        host_id TEXT NOT NULL,
+        node    TEXT NOT NULL,
+        away    INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (room_id, host_id)
+    );
+    CREATE TABLE IF NOT EXISTS room_spectators (
+        room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+        user_id TEXT NOT NULL,
+        node    TEXT NOT NULL,
+        sockets INTEGER NOT NULL,
+        PRIMARY KEY (room_id, user_id, node)
+    );
+";
+
+/// Admits a member, noting the last broadcast recorded before them
+const INSERT_MEMBER: &str = "
+    INSERT INTO room_users (room_id, user_id, node, joined_at, can_host, history_seen)
+    VALUES (?1, ?2, ?3, ?4, ?5, (SELECT COALESCE(MAX(id), 0) FROM room_history WHERE room_id = ?1))
+";
+
+/// File-backed storage. Rooms survive restarts; memberships, host presence
+/// and spectators are tied to live sockets, so each node drops the ones it
+/// owned when it opens the database. Several nodes may share one file.
+pub struct SqliteRoomStorage {
+    conn: Mutex<Connection>,
+    node_id: String,
+}
+
+impl SqliteRoomStorage {
+    pub fn open(path: &str, node_id: &str) -> Result<Self, rusqlite::Error> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)?;
+        add_column_if_missing(&conn, "room_users", "node TEXT NOT NULL DEFAULT ''")?;
+        add_column_if_missing(&conn, "room_users", "joined_at INTEGER NOT NULL DEFAULT 0")?;
+        add_column_if_missing(
+            &conn,
+            "room_users",
+            "history_seen INTEGER NOT NULL DEFAULT 0",
+        )?;
+        add_column_if_missing(&conn, "room_users", "can_host INTEGER NOT NULL DEFAULT 0")?;
+        add_column_if_missing(&conn, "room_waitlist", "node TEXT NOT NULL DEFAULT ''")?;
+        add_column_if_missing(
+            &conn,
+            "room_waitlist",
+            "can_host INTEGER NOT NULL DEFAULT 0",
+        )?;
+        // Rows without a node come from a single-node version and are stale
+        conn.execute("DELETE FROM room_users WHERE node IN (?1, '')", [node_id])?;
+        conn.execute(
+            "DELETE FROM room_waitlist WHERE node IN (?1, '')",
+            [node_id],
+        )?;
+        conn.execute("DELETE FROM room_hosts WHERE node = ?1", [node_id])?;
+        conn.execute("DELETE FROM room_spectators WHERE node = ?1", [node_id])?;
+
+        tracing::info!("SQLite room storage opened at {} as node {}", path, node_id);
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            node_id: node_id.to_string(),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Bring a table created by an older version up to date
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<()> {
+    let name = column.split_whitespace().next().unwrap_or(column);
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{table}') WHERE name = ?1"
+        ))?
+        .exists([name])?;
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column}"))?;
+    }
+    Ok(())
+}
+
+fn decode_room(data: &str) -> Option<Room> {
+    match serde_json::from_str(data) {
+        Ok(room) => Some(room),
+        Err(e) => {
+            tracing::error!("Failed to decode stored room: {}", e);
+            None
+        }
+    }
+}
+
+fn load_room(conn: &Connection, room_id: &str) -> rusqlite::Result<Option<Room>> {
+    let data: Option<String> = conn
+        .query_row("SELECT data FROM rooms WHERE id = ?1", [room_id], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    Ok(data.as_deref().and_then(decode_room))
+}
+
+fn exists(tx: &Transaction, sql: &str, room_id: &str, user_id: &str) -> rusqlite::Result<bool> {
+    tx.query_row(sql, params![room_id, user_id], |_| Ok(()))
+        .optional()
+        .map(|row| row.is_some())
+}
+
+fn count(tx: &Transaction, sql: &str, room_id: &str) -> rusqlite::Result<usize> {
+    tx.query_row(sql, [room_id], |row| row.get::<_, i64>(0))
+        .map(|n| n as usize)
+}
+
+fn join_room(
+    tx: &Transaction,
+    node_id: &str,
+    room_id: &str,
+    user_id: &str,
+    can_host: bool,
+) -> rusqlite::Result<JoinOutcome> {
+    let Some(room) = load_room(tx, room_id)? else {
+        return Ok(JoinOutcome::RoomNotFound);
+    };
+
+    if exists(
+        tx,
+        "SELECT 1 FROM room_users WHERE room_id = ?1 AND user_id = ?2",
+        room_id,
+        user_id,
+    )? {
+        return Ok(JoinOutcome::AlreadyMember);
+    }
+
+    let position: Option<i64> = tx
+        .query_row(
+            "SELECT COUNT(*) FROM room_waitlist w, room_waitlist me
+             WHERE me.room_id = ?1 AND me.user_id = ?2
+               AND w.room_id = ?1 AND w.seq <= me.seq
+             HAVING COUNT(*) > 0",
+            params![room_id, user_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(position) = position {
+        return Ok(JoinOutcome::Waitlisted(position as usize));
+    }
+
+    let members = count(
+        tx,
+        "SELECT COUNT(*) FROM room_users WHERE room_id = ?1",
+        room_id,
+    )?;
+    if !room.is_full(members) {
+        tx.execute(
+            INSERT_MEMBER,
+            params![
+                room_id,
+                user_id,
+                node_id,
+                unix_now_millis() as i64,
+                can_host
+            ],
+        )?;
+        return Ok(JoinOutcome::Joined);
+    }
+
+    if room.waitlist {
+        tx.execute(
+            "INSERT INTO room_waitlist (room_id, user_id, node, can_host) VALUES (?1, ?2, ?3, ?4)",
+            params![room_id, user_id, node_id, can_host],
+        )?;
+        let position = count(
+            tx,
+            "SELECT COUNT(*) FROM room_waitlist WHERE room_id = ?1",
+            room_id,
+        )?;
+        return Ok(JoinOutcome::Waitlisted(position));
+    }
+
+    Ok(JoinOutcome::Full)
+}
+
+fn leave_room(tx: &Transaction, room_id: &str, user_id: &str) -> rusqlite::Result<LeaveOutcome> {
+    let removed = tx.execute(
+        "DELETE FROM room_users WHERE room_id = ?1 AND user_id = ?2",
+        params![room_id, user_id],
+    )?;
+    if removed == 0 {
+        let dequeued = tx.execute(
+            "DELETE FROM room_waitlist WHERE room_id = ?1 AND user_id = ?2",
+            params![room_id, user_id],
+        )?;
+        return Ok(if dequeued == 0 {
+            LeaveOutcome::NotInRoom
+        } else {
+            LeaveOutcome::LeftWaitlist
+        });
+    }
+
+    let promoted = promote_waitlisted(tx, room_id)?;
+    Ok(LeaveOutcome::Left { promoted })
+}
+
+/// Move the first waitlisted user into a free slot of an open room
+fn promote_waitlisted(tx: &Transaction, room_id: &str) -> rusqlite::Result<Option<UserId>> {
+    let Some(room) = load_room(tx, room_id)? else {
+        return Ok(None);
+    };
+    let members = count(
+        tx,
+        "SELECT COUNT(*) FROM room_users WHERE room_id = ?1",
+        room_id,
+    )?;
+    if room.state != RoomState::Open || room.is_full(members) {
+        return Ok(None);
+    }
+
+    // Promoted users keep the node of the connection that is waiting
+    let promoted: Option<(String, String, bool)> = tx
+        .query_row(
+            "DELETE FROM room_waitlist
+             WHERE seq = (SELECT MIN(seq) FROM room_waitlist WHERE room_id = ?1)
+             RETURNING user_id, node, can_host",
+            [room_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+    let Some((promoted, node, can_host)) = promoted else {
+        return Ok(None);
+    };
+    tx.execute(
+        INSERT_MEMBER,
+        params![room_id, promoted, node, unix_now_millis() as i64, can_host],
+    )?;
+    Ok(Some(UserId::new(promoted)))
+}
+
+/// Append a broadcast to the room history, evicting what the limits no
+/// longer allow. The newest message always stays, so its id stays the
+/// highest one recorded.
+fn record_history(
+    tx: &Transaction,
+    room_id: &str,
+    message: &MessagePayload,
+) -> rusqlite::Result<()> {
+    let Some(limits) = load_room(tx, room_id)?.and_then(|room| room.history) else {
+        return Ok(());
+    };
+    let message = serde_json::to_string(message)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    let now = unix_now_millis();
+    let id: i64 = tx.query_row(
+        "INSERT INTO room_history (room_id, id, sent_at, message)
+         VALUES (?1, (SELECT COALESCE(MAX(id), 0) + 1 FROM room_history WHERE room_id = ?1), ?2, ?3)
+         RETURNING id",
+        params![room_id, now as i64, message],
+        |row| row.get(0),
+    )?;
+    tx.execute(
+        "DELETE FROM room_history WHERE room_id = ?1 AND (id <= ?2 OR sent_at < ?3)",
+        params![
+            room_id,
+            id.saturating_sub(limits.max_messages as i64),
+            limits.cutoff(now) as i64
+        ],
+    )?;
+    Ok(())
+}
+
+fn room_users(tx: &Transaction, room_id: &str) -> rusqlite::Result<Vec<UserId>> {
+    let mut stmt = tx.prepare("SELECT user_id FROM room_users WHERE room_id = ?1")?;
+    stmt.query_map([room_id], |row| row.get::<_, String>(0).map(UserId::new))?
+        .collect()
+}
+
+fn member_from_row(row: &rusqlite::Row) -> rusqlite::Result<Member> {
+    Ok(Member {
+        user_id: UserId::new(row.get::<_, String>(0)?),
+        joined_at: row.get::<_, i64>(1)? as u64,
+        history_seen: row.get::<_, i64>(2)? as u64,
+        can_host: row.get(3)?,
+    })
+}
+
+/// Run a SQLite call from async code. SQLite blocks on disk I/O, so on a
+/// multi-threaded runtime the worker first hands its other tasks over:
+/// one slow fsync must not stall the sockets served by the same thread.
+fn blocking<T>(call: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(call)
+        }
+        _ => call(),
+    }
+}
+
+fn log_err<T>(op: &str, result: Result<T, rusqlite::Error>) -> Option<T> {
+    result
+        .map_err(|e| tracing::error!("SQLite {} failed: {}", op, e))
+        .ok()
+}
+
+impl RoomStore for SqliteRoomStorage {
+    fn create_room(&self, room: Room) -> Result<RoomId, CreateRoomError> {
+        blocking(|| {
+            let data = serde_json::to_string(&room)
+                .map_err(|e| CreateRoomError::Backend(e.to_string()))?;
+            let inserted = self
+                .conn()
+                .execute(
+                    "INSERT OR IGNORE INTO rooms (id, data) VALUES (?1, ?2)",
+                    params![room.id.to_string(), data],
+                )
+                .map_err(|e| CreateRoomError::Backend(e.to_string()))?;
+
+            if inserted == 0 {
+                return Err(CreateRoomError::RoomAlreadyExists);
+            }
+            Ok(room.id)
+        })
+    }
+
+    fn get_room(&self, room_id: &str) -> Option<Room> {
+        blocking(|| log_err("get_room", load_room(&self.conn(), room_id)).flatten())
+    }
+
+    fn remove_room(&self, room_id: &str) -> Option<Room> {
+        blocking(|| {
+            let data: Option<String> = log_err(
+                "remove_room",
+                self.conn()
+                    .query_row(
+                        "DELETE FROM rooms WHERE id = ?1 RETURNING data",
+                        [room_id],
+                        |row| row.get(0),
+                    )
+                    .optional(),
+            )?;
+            data.as_deref().and_then(decode_room)
+        })
+    }
+
+    fn update_room(&self, room_id: &str, update: &mut dyn FnMut(&mut Room)) -> Option<Room> {
+        blocking(|| {
+            let mut conn = self.conn();
+            log_err(
+                "update_room",
+                conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                    .and_then(|tx| {
+                        let Some(mut room) = load_room(&tx, room_id)? else {
+                            return Ok(None);
+                        };
+                        update(&mut room);
+                        let data = serde_json::to_string(&room)
+                            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+                        tx.execute(
+                            "UPDATE rooms SET data = ?2 WHERE id = ?1",
+                            params![room_id, data],
+                        )?;
+                        tx.commit()?;
+                        Ok(Some(room))
+                    }),
+            )
+            .flatten()
+        })
+    }
+
+    fn get_rooms_paginated(&self, page: usize, size: usize) -> (Vec<Room>, usize) {
+        blocking(|| {
+            let conn = self.conn();
+            let total: i64 = log_err(
+                "count rooms",
+                conn.query_row("SELECT COUNT(*) FROM rooms", [], |row| row.get(0)),
+            )
+            .unwrap_or(0);
+
+            let rooms = log_err(
+                "get_rooms_paginated",
+                conn.prepare("SELECT data FROM rooms ORDER BY rowid LIMIT ?1 OFFSET ?2")
+                    .and_then(|mut stmt| {
+                        stmt.query_map(params![size as i64, (page * size) as i64], |row| {
+                            row.get::<_, String>(0)
+                        })?
+                        .collect::<Result<Vec<_>, _>>()
+                    }),
+            )
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|data| decode_room(data))
+            .collect();
+
+            (rooms, total as usize)
+        })
+    }
+
+    fn count_rooms_by_type(&self) -> Vec<(String, usize)> {
+        blocking(|| {
+            let conn = self.conn();
+            log_err(
+                "count_rooms_by_type",
+                conn.prepare(
+                    "SELECT json_extract(data, '$.room_type'), COUNT(*) FROM rooms GROUP BY 1",
+                )
+                .and_then(|mut stmt| {
+                    stmt.query_map([], |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as usize))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+                }),
+            )
+            .unwrap_or_default()
+        })
+    }
+
+    fn add_user_to_room(&self, room_id: &str, user_id: UserId, can_host: bool) -> JoinOutcome {
+        blocking(|| {
+            let mut conn = self.conn();
+            log_err(
+                "add_user_to_room",
+                conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                    .and_then(|tx| {
+                        let outcome =
+                            join_room(&tx, &self.node_id, room_id, user_id.as_str(), can_host)?;
+                        tx.commit()?;
+                        Ok(outcome)
+                    }),
+            )
+            .unwrap_or(JoinOutcome::RoomNotFound)
+        })
+    }
+
+    fn remove_user_from_room(&self, room_id: &str, user_id: &UserId) -> LeaveOutcome {
+        blocking(|| {
+            let mut conn = self.conn();
+            log_err(
+                "remove_user_from_room",
+                conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                    .and_then(|tx| {
+                        let outcome = leave_room(&tx, room_id, user_id.as_str())?;
+                        tx.commit()?;
+                        Ok(outcome)
+                    }),
+            )
+            .unwrap_or(LeaveOutcome::NotInRoom)
+        })
+    }
+
+    fn promote_waitlisted(&self, room_id: &str) -> Vec<UserId> {
+        blocking(|| {
+            let mut conn = self.conn();
+            log_err(
+                "promote_waitlisted",
+                conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                    .and_then(|tx| {
+                        let mut promoted = Vec::new();
+                        while let Some(user_id) = promote_waitlisted(&tx, room_id)? {
+                            promoted.push(user_id);
+                        }
+                        tx.commit()?;
+                        Ok(promoted)
+                    }),
+            )
+            .unwrap_or_default()
+        })
+    }
+
+    fn is_user_in_room(&self, room_id: &str, user_id: &UserId) -> bool {
+        blocking(|| {
+            log_err(
+                "is_user_in_room",
+                self.conn()
+                    .query_row(
+                        "SELECT 1 FROM room_users WHERE room_id = ?1 AND user_id = ?2",
+                        params![room_id, user_id.as_str()],
+                        |_| Ok(()),
+                    )
+                    .optional(),
+            )
+            .flatten()
+            .is_some()
+        })
+    }
+
+    fn get_room_user_count(&self, room_id: &str) -> usize {
+        blocking(|| {
+            log_err(
+                "get_room_user_count",
+                self.conn().query_row(
+                    "SELECT COUNT(*) FROM room_users WHERE room_id = ?1",
+                    [room_id],
+                    |row| row.get::<_, i64>(0),
+                ),
+            )
+            .unwrap_or(0) as usize
+        })
+    }
+
+    fn get_room_users(&self, room_id: &str) -> Vec<UserId> {
+        blocking(|| {
+            log_err(
+                "get_room_users",
+                self.conn()
+                    .prepare("SELECT user_id FROM room_users WHERE room_id = ?1")
+                    .and_then(|mut stmt| {
+                        stmt.query_map([room_id], |row| row.get::<_, String>(0).map(UserId::new))?
+                            .collect::<Result<Vec<_>, _>>()
+                    }),
+            )
+            .unwrap_or_default()
+        })
+    }
+
+    fn get_room_members(&self, room_id: &str) -> Vec<Member> {
+        blocking(|| {
+            log_err(
+                "get_room_members",
+                self.conn()
+                    .prepare(
+                        "SELECT user_id, joined_at, history_seen, can_host FROM room_users
+                         WHERE room_id = ?1",
+                    )
+                    .and_then(|mut stmt| {
+                        stmt.query_map([room_id], member_from_row)?
+                            .collect::<Result<Vec<_>, _>>()
+                    }),
+            )
+            .unwrap_or_default()
+        })
+    }
+
+    fn get_room_member(&self, room_id: &str, user_id: &UserId) -> Option<Member> {
+        blocking(|| {
+            log_err(
+                "get_room_member",
+                self.conn()
+                    .query_row(
+                        "SELECT user_id, joined_at, history_seen, can_host FROM room_users
+                         WHERE room_id = ?1 AND user_id = ?2",
+                        params![room_id, user_id.as_str()],
+                        member_from_row,
+                    )
+                    .optional(),
+            )
+            .flatten()
+        })
+    }
+
+    fn get_waitlist_count(&self, room_id: &str) -> usize {
+        blocking(|| {
+            log_err(
+                "get_waitlist_count",
+                self.conn().query_row(
+                    "SELECT COUNT(*) FROM room_waitlist WHERE room_id = ?1",
+                    [room_id],
+                    |row| row.get::<_, i64>(0),
+                ),
+            )
+            .unwrap_or(0) as usize
+        })
+    }
+
+    fn clear_room_users(&self, room_id: &str) -> Vec<UserId> {
+        blocking(|| {
+            let mut conn = self.conn();
+            log_err(
+                "clear_room_users",
+                conn.transaction().and_then(|tx| {
+                    let mut users = Vec::new();
+                    for sql in [
+                        "DELETE FROM room_users WHERE room_id = ?1 RETURNING user_id",
+                        "DELETE FROM room_waitlist WHERE room_id = ?1 RETURNING user_id",
+                    ] {
+                        let mut stmt = tx.prepare(sql)?;
+                        let removed = stmt
+                            .query_map([room_id], |row| row.get::<_, String>(0).map(UserId::new))?
+                            .collect::<Result<Vec<_>, _>>()?;
+                        users.extend(removed);
+                    }
+                    tx.commit()?;
+                    Ok(users)
+                }),
+            )
+            .unwrap_or_default()
+        })
+    }
+
+    fn record_broadcast(&self, room_id: &str, message: MessagePayload) -> Vec<UserId> {
+        blocking(|| {
+            let mut conn = self.conn();
+            log_err(
+                "record_broadcast",
+                conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                    .and_then(|tx| {
+                        record_history(&tx, room_id, &message)?;
+                        let users = room_users(&tx, room_id)?;
+                        tx.commit()?;
+                        Ok(users)
+                    }),
+            )
+            .unwrap_or_default()
+        })
+    }
+
+    fn get_history(&self, room_id: &str, up_to: Option<u64>) -> Option<Vec<HistoryEntry>> {
+        blocking(|| {
+            let conn = self.conn();
+            let limits = log_err("get_history", load_room(&conn, room_id))??.history?;
+            let up_to = up_to.map_or(i64::MAX, |up_to| up_to as i64);
+            let cutoff = limits.cutoff(unix_now_millis()) as i64;
+            log_err(
+                "get_history",
+                conn.prepare(
+                    "SELECT id, sent_at, message FROM room_history
+                 WHERE room_id = ?1 AND id <= ?2 AND sent_at >= ?3 ORDER BY id",
+                )
+                .and_then(|mut stmt| {
+                    stmt.query_map(params![room_id, up_to, cutoff], |row| {
+                        let message: String = row.get(2)?;
+                        Ok(HistoryEntry {
+                            id: row.get::<_, i64>(0)? as u64,
+                            sent_at: row.get::<_, i64>(1)? as u64,
+                            message: serde_json::from_str(&message).map_err(|e| {
+                                rusqlite::Error::FromSqlConversionFailure(
+                                    2,
+                                    rusqlite::types::Type::Text,
+                                    Box::new(e),
+                                )
+                            })?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+                }),
+            )
+        })
+    }
+
+    fn set_host_presence(&self, room_id: &str, host_id: &UserId, presence: Option<HostPresence>) {
+        blocking(|| {
+            let conn = self.conn();
+            let params = params![room_id, host_id.as_str(), self.node_id];
+            let result = match presence {
+            Some(HostPresence::Connected) => conn.execute(
+                "INSERT INTO room_hosts (room_id, host_id, node, away)
+                 SELECT ?1, ?2, ?3, 0 WHERE EXISTS (SELECT 1 FROM rooms WHERE id = ?1)
+                 ON CONFLICT (room_id, host_id) DO UPDATE SET node = excluded.node, away = 0",
+                params,
+            ),
+            Some(HostPresence::Away) => conn.execute(
+                "UPDATE room_hosts SET away = 1 WHERE room_id = ?1 AND host_id = ?2 AND node = ?3",
+                params,
+            ),
+            None => conn.execute(
+                "DELETE FROM room_hosts WHERE room_id = ?1 AND host_id = ?2 AND node = ?3",
+                params,
+            ),
+        };
+            log_err("set_host_presence", result);
+        })
+    }
+
+    fn get_host_presence(&self, room_id: &str) -> Vec<(UserId, HostPresence)> {
+        blocking(|| {
+            log_err(
+                "get_host_presence",
+                self.conn()
+                    .prepare("SELECT host_id, away FROM room_hosts WHERE room_id = ?1")
+                    .and_then(|mut stmt| {
+                        stmt.query_map([room_id], |row| {
+                            let presence = match row.get::<_, bool>(1)? {
+                                true => HostPresence::Away,
+                                false => HostPresence::Connected,
+                            };
+                            Ok((UserId::new(row.get::<_, String>(0)?), presence))
+                        })?
+                        .collect::<Result<Vec<_>, _>>()
+                    }),
+            )
+            .unwrap_or_default()
+        })
+    }
+
+    fn add_spectator(&self, room_id: &str, user_id: &UserId) -> bool {
+        blocking(|| {
+            let result = self.conn().execute(
+                "INSERT INTO room_spectators (room_id, user_id, node, sockets)
+             SELECT ?1, ?2, ?3, 1 WHERE EXISTS (SELECT 1 FROM rooms WHERE id = ?1)
+             ON CONFLICT (room_id, user_id, node) DO UPDATE SET sockets = sockets + 1",
+                params![room_id, user_id.as_str(), self.node_id],
+            );
+            log_err("add_spectator", result).is_some_and(|added| added > 0)
+        })
+    }
+
+    fn remove_spectator(&self, room_id: &str, user_id: &UserId) {
+        blocking(|| {
+            // Only this node writes its rows, and it holds the connection
+            let conn = self.conn();
+            let params = params![room_id, user_id.as_str(), self.node_id];
+            let result = conn
+                .execute(
+                    "UPDATE room_spectators SET sockets = sockets - 1
+                 WHERE room_id = ?1 AND user_id = ?2 AND node = ?3",
+                    params,
+                )
+                .and_then(|_| {
+                    conn.execute(
+                        "DELETE FROM room_spectators
+                     WHERE room_id = ?1 AND user_id = ?2 AND node = ?3 AND sockets <= 0",
+                        params,
+                    )
+                });
+            log_err("remove_spectator", result);
+        })
+    }
+
+    fn get_spectator_count(&self, room_id: &str) -> usize {
+        blocking(|| {
+            log_err(
+                "get_spectator_count",
+                self.conn().query_row(
+                    "SELECT COALESCE(SUM(sockets), 0) FROM room_spectators WHERE room_id = ?1",
+                    [room_id],
+                    |row| row.get::<_, i64>(0),
+                ),
+            )
+            .map_or(0, |count| count as usize)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        history::HistoryLimits,
+        room::{Room, RoomType},
+    };
+
+    /// Database file removed with its WAL files once the test is done.
+    /// Declare it before the storages that use it, so it is dropped last.
+    struct TempDb(String);
+
+    impl TempDb {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("rooms-{}.db", uuid::Uuid::new_v4()));
+            Self(path.to_str().unwrap().to_string())
+        }
+
+        fn open(&self, node_id: &str) -> SqliteRoomStorage {
+            SqliteRoomStorage::open(&self.0, node_id).unwrap()
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{suffix}", self.0));
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn runs_on_runtime_workers() {
+        let db = TempDb::new();
+        let storage = db.open("node-1");
+        let room_id = storage
+            .create_room(Room::new(UserId::new("host"), RoomType::new("game")))
+            .unwrap()
+            .to_string();
+        let user = UserId::new("alice");
+        assert_eq!(
+            storage.add_user_to_room(&room_id, user.clone(), false),
+            JoinOutcome::Joined
+        );
+        assert!(storage.is_user_in_room(&room_id, &user));
+    }
+
+    #[test]
+    fn promoted_users_keep_their_host_role() {
+        let db = TempDb::new();
+        let storage = db.open("node-1");
+        let room =
+            Room::new(UserId::new("host"), RoomType::new("game")).with_capacity(Some(1), true);
+        let room_id = storage.create_room(room).unwrap().to_string();
+        let [alice, bob] = ["alice", "bob"].map(UserId::new);
+        storage.add_user_to_room(&room_id, alice.clone(), false);
+        storage.add_user_to_room(&room_id, bob.clone(), true);
+        assert!(!storage.get_room_member(&room_id, &alice).unwrap().can_host);
+
+        storage.remove_user_from_room(&room_id, &alice);
+        assert!(storage.get_room_member(&room_id, &bob).unwrap().can_host);
+    }
+
+    #[test]
+    fn upgrades_tables_without_node_column() {
+        let db = TempDb::new();
+        {
+            let conn = Connection::open(&db.0).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE rooms (id TEXT PRIMARY KEY NOT NULL, data TEXT NOT NULL);
+                 CREATE TABLE room_users (room_id TEXT NOT NULL, user_id TEXT NOT NULL,
+                     PRIMARY KEY (room_id, user_id));
+                 CREATE TABLE room_waitlist (seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                     room_id TEXT NOT NULL, user_id TEXT NOT NULL, UNIQUE (room_id, user_id));
+                 INSERT INTO room_users VALUES ('room', 'alice');
+                 INSERT INTO room_waitlist (room_id, user_id) VALUES ('room', 'bob');",
+            )
+            .unwrap();
+        }
+
+        let storage = db.open("node-1");
+        let conn = storage.conn();
+        let users: i64 = conn
+            .query_row("SELECT COUNT(*) FROM room_users", [], |row| row.get(0))
+            .unwrap();
+        let waiting: i64 = conn
+            .query_row("SELECT COUNT(*) FROM room_waitlist", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!((users, waiting), (0, 0));
+    }
+
+    #[test]
+    fn keeps_history_in_its_own_table() {
+        let db = TempDb::new();
+        let storage = db.open("node-1");
+        let room = Room::new(UserId::new("host"), RoomType::new("game")).with_history(Some(
+            HistoryLimits {
+                max_messages: 3,
+                max_age_secs: None,
+            },
+        ));
+        let room_id = storage.create_room(room).unwrap().to_string();
+        let [alice, bob] = ["alice", "bob"].map(UserId::new);
+
+        storage.add_user_to_room(&room_id, alice.clone(), false);
+        for n in 1..=3 {
+            assert_eq!(
+                storage.record_broadcast(&room_id, serde_json::json!(n)),
+                vec![alice.clone()]
+            );
+        }
+        storage.add_user_to_room(&room_id, bob.clone(), false);
+        storage.record_broadcast(&room_id, serde_json::json!(4));
+
+        let seen = storage
+            .get_room_member(&room_id, &bob)
+            .unwrap()
+            .history_seen;
+        assert_eq!(seen, 3);
+        let replayed = storage.get_history(&room_id, Some(seen)).unwrap();
+        let messages: Vec<_> = replayed.iter().map(|entry| entry.message.clone()).collect();
+        assert_eq!(messages, vec![serde_json::json!(2), serde_json::json!(3)]);
+        assert_eq!(storage.get_history(&room_id, None).unwrap().len(), 3);
+
+        // The room row does not grow with the history
+        let data: String = storage
+            .conn()
+            .query_row("SELECT data FROM rooms WHERE id = ?1", [&room_id], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(!data.contains("entries"));
+    }
+
+    #[test]
+    fn host_presence_belongs_to_the_last_node() {
+        let db = TempDb::new();
+        let node_a = db.open("node-a");
+        let node_b = db.open("node-b");
+        let host = UserId::new("host");
+        let room = Room::new(host.clone(), RoomType::new("game"));
+        let room_id = node_a.create_room(room).unwrap().to_string();
+
+        // The host moved to node B before node A noticed the drop
+        node_a.set_host_presence(&room_id, &host, Some(HostPresence::Connected));
+        node_b.set_host_presence(&room_id, &host, Some(HostPresence::Connected));
+        node_a.set_host_presence(&room_id, &host, Some(HostPresence::Away));
+        node_a.set_host_presence(&room_id, &host, None);
+        assert_eq!(
+            node_a.get_host_presence(&room_id),
+            vec![(host.clone(), HostPresence::Connected)]
+        );
+
+        // Node B restarts
+        drop(node_b);
+        let node_b = db.open("node-b");
+        assert!(node_b.get_host_presence(&room_id).is_empty());
+    }
+
+    #[test]
+    fn spectators_are_counted_per_node() {
+        let db = TempDb::new();
+        let node_a = db.open("node-a");
+        let node_b = db.open("node-b");
+        let room = Room::new(UserId::new("host"), RoomType::new("game"));
+        let room_id = node_a.create_room(room).unwrap().to_string();
+        let spectator = UserId::new("spectator");
+
+        assert!(!node_a.add_spectator("missing", &spectator));
+        assert!(node_a.add_spectator(&room_id, &spectator));
+        assert!(node_a.add_spectator(&room_id, &spectator));
+        assert!(node_b.add_spectator(&room_id, &spectator));
+        node_a.remove_spectator(&room_id, &spectator);
+        assert_eq!(node_b.get_spectator_count(&room_id), 2);
+
+        // Node A crashes with a socket still open
+        drop(node_a);
+        let node_a = db.open("node-a");
+        assert_eq!(node_a.get_spectator_count(&room_id), 1);
+        node_b.remove_spectator(&room_id, &spectator);
+        assert_eq!(node_a.get_spectator_count(&room_id), 0);
+    }
+}
@@ -0,0 +1,166 @@
+use axum::{extract::ws::Message as WsMessage, http::HeaderValue};
+use serde::{Serialize, de::DeserializeOwned};
+
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Wire encoding of a connection, negotiated through `Sec-WebSocket-Protocol`.
+/// JSON goes in text frames, the binary encodings in binary frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Codec {
+    /// Subprotocols offered to clients, in order of preference
+    pub const PROTOCOLS: [&str; 3] = ["msgpack", "cbor", "json"];
+
+    /// The codec for the subprotocol the server agreed on; JSON when the
+    /// client asked for none
+    pub fn from_protocol(protocol: Option<&HeaderValue>) -> Self {
+        match protocol.and_then(|value| value.to_str().ok()) {
+            Some("msgpack") => Codec::MessagePack,
+            Some("cbor") => Codec::Cbor,
+            _ => Codec::Json,
+        }
+    }
+
+    /// Subprotocol name of the codec
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Json => "json",
+            Codec::MessagePack => "msgpack",
+            Codec::Cbor => "cbor",
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, msg: &T) -> Result<WsMessage, CodecError> {
+        Ok(match self {
+            Codec::Json => WsMessage::Text(serde_json::to_string(msg)?.into()),
+            Codec::MessagePack => WsMessage::Binary(rmp_serde::to_vec_named(msg)?.into()),
+            Codec::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(msg, &mut buffer)?;
+                WsMessage::Binary(buffer.into())
+            }
+        })
+    }
+
+    /// Decode a data frame. Text frames are always JSON, so clients can
+    /// fall back to it for debugging on any connection.
+    pub fn decode<T: DeserializeOwned>(self, frame: &WsMessage) -> Result<T, CodecError> {
+        match (frame, self) {
+            (WsMessage::Text(text), _) => Ok(serde_json::from_str(text)?),
+            (WsMessage::Binary(bytes), Codec::Json) => Ok(serde_json::from_slice(bytes)?),
+            (WsMessage::Binary(bytes), Codec::MessagePack) => Ok(rmp_serde::from_slice(bytes)?),
+            (WsMessage::Binary(bytes), Codec::Cbor) => Ok(ciborium::from_reader(&bytes[..])?),
+            _ => Err("not a data frame".into()),
+        }
+    }
+}
+
+/// Whether decoding failed on the decoder's own nesting limit rather than
+/// on a malformed frame: serde_json stops at 128 levels, ciborium at 256
+/// and rmp-serde at 1024, whatever `MessageLimits::max_depth` allows.
+pub fn is_too_deep(error: &CodecError) -> bool {
+    if let Some(error) = error.downcast_ref::<serde_json::Error>() {
+        // serde_json does not expose the error code
+        return error.to_string().starts_with("recursion limit exceeded");
+    }
+    if let Some(error) = error.downcast_ref::<rmp_serde::decode::Error>() {
+        return matches!(error, rmp_serde::decode::Error::DepthLimitExceeded);
+    }
+    error
+        .downcast_ref::<ciborium::de::Error<std::io::Error>>()
+        .is_some_and(|error| matches!(error, ciborium::de::Error::RecursionLimitExceeded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{event::DisconnectReason, message::ToUserMessage, user::UserId},
+        websocket::delivery::Delivery,
+    };
+
+    #[test]
+    fn messages_survive_a_round_trip() {
+        let user_id = UserId::new("user");
+        let messages = [
+            ToUserMessage::message(
+                user_id.clone(),
+                serde_json::json!({ "text": "hi", "n": [1, -2.5, null, true] }),
+            ),
+            ToUserMessage::disconnect(user_id.clone(), DisconnectReason::Banned),
+            ToUserMessage::binary(user_id, vec![0, 1, 255].into()),
+        ];
+        for codec in [Codec::Json, Codec::MessagePack, Codec::Cbor] {
+            for msg in &messages {
+                let frame = codec.encode(msg).unwrap();
+                assert_eq!(matches!(frame, WsMessage::Text(_)), codec == Codec::Json);
+                let decoded: ToUserMessage = codec.decode(&frame).unwrap();
+                assert_eq!(
+                    serde_json::to_value(&decoded).unwrap(),
+                    serde_json::to_value(msg).unwrap(),
+                    "{}",
+                    codec.name()
+                );
+            }
+        }
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Sequenced {
+        seq: u64,
+    }
+
+    #[test]
+    fn sequenced_messages_keep_their_fields_in_every_codec() {
+        let user_id = UserId::new("user");
+        let messages = [
+            ToUserMessage::message(user_id.clone(), serde_json::json!({ "text": "hi" })),
+            ToUserMessage::binary(user_id, vec![0, 1, 255].into()),
+        ];
+        for codec in [Codec::Json, Codec::MessagePack, Codec::Cbor] {
+            let mut delivery = Delivery::new(false, codec);
+            for (seq, msg) in (1..).zip(&messages) {
+                let frame = delivery.frame(msg).unwrap();
+                let sequenced: Sequenced = codec.decode(&frame).unwrap();
+                assert_eq!(sequenced.seq, seq, "{}", codec.name());
+
+                let decoded: ToUserMessage = codec.decode(&frame).unwrap();
+                assert_eq!(
+                    serde_json::to_value(&decoded).unwrap(),
+                    serde_json::to_value(msg).unwrap(),
+                    "{}",
+                    codec.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn text_frames_are_json_on_any_connection() {
+        let frame = WsMessage::Text(r#"{"event":"GET_STATE"}"#.into());
+        for codec in [Codec::Json, Codec::MessagePack, Codec::Cbor] {
+            let value: serde_json::Value = codec.decode(&frame).unwrap();
+            assert_eq!(value["event"], "GET_STATE");
+        }
+        assert!(
+            Codec::Cbor
+                .decode::<serde_json::Value>(&WsMessage::Ping(vec![].into()))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn negotiates_by_subprotocol() {
+        for codec in [Codec::Json, Codec::MessagePack, Codec::Cbor] {
+            let protocol = HeaderValue::from_static(codec.name());
+            assert_eq!(Codec::from_protocol(Some(&protocol)), codec);
+        }
+        assert_eq!(Codec::from_protocol(None), Codec::Json);
+    }
+}
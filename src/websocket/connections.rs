@@ -0,0 +1,108 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+use super::codec::Codec;
+use crate::domain::{room::unix_now_millis, user::UserId};
+
+/// What the upgrade request told about the client
+#[derive(Debug, Clone, Copy)]
+pub struct Peer {
+    pub remote_addr: SocketAddr,
+    /// The token carries the Host role
+    pub can_host: bool,
+}
+
+/// Metadata of a live socket
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionInfo {
+    pub remote_addr: SocketAddr,
+    /// May take over the room as its host
+    pub can_host: bool,
+    /// Negotiated encoding: `json`, `msgpack` or `cbor`
+    pub protocol: &'static str,
+    /// Unix time in milliseconds the socket was opened
+    pub connected_since: u64,
+    #[serde(skip)]
+    id: u64,
+}
+
+/// Sockets open on this instance: hosts and users by room and user id.
+/// A newer socket of the same client replaces the entry of the older one.
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    hosts: DashMap<(String, UserId), ConnectionInfo>,
+    users: DashMap<(String, UserId), ConnectionInfo>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn info(&self, peer: Peer, codec: Codec) -> ConnectionInfo {
+        ConnectionInfo {
+            remote_addr: peer.remote_addr,
+            can_host: peer.can_host,
+            protocol: codec.name(),
+            connected_since: unix_now_millis(),
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Record an opened host socket. Returns the id to close it with.
+    pub fn open_host(&self, room_id: &str, host_id: &UserId, peer: Peer, codec: Codec) -> u64 {
+        let info = self.info(peer, codec);
+        let id = info.id;
+        self.hosts
+            .insert((room_id.to_string(), host_id.clone()), info);
+        id
+    }
+
+    /// Forget a host socket unless a newer one already took its place
+    pub fn close_host(&self, room_id: &str, host_id: &UserId, id: u64) {
+        self.hosts
+            .remove_if(&(room_id.to_string(), host_id.clone()), |_, info| {
+                info.id == id
+            });
+    }
+
+    /// Host sockets of a room, oldest first
+    pub fn hosts(&self, room_id: &str) -> Vec<(UserId, ConnectionInfo)> {
+        let mut hosts: Vec<_> = self
+            .hosts
+            .iter()
+            .filter(|entry| entry.key().0 == room_id)
+            .map(|entry| (entry.key().1.clone(), entry.value().clone()))
+            .collect();
+        hosts.sort_by_key(|(_, info)| info.connected_since);
+        hosts
+    }
+
+    /// Record an opened user socket. Returns the id to close it with.
+    pub fn open_user(&self, room_id: &str, user_id: &UserId, peer: Peer, codec: Codec) -> u64 {
+        let info = self.info(peer, codec);
+        let id = info.id;
+        self.users
+            .insert((room_id.to_string(), user_id.clone()), info);
+        id
+    }
+
+    /// Forget a user socket unless a newer one already took its place
+    pub fn close_user(&self, room_id: &str, user_id: &UserId, id: u64) {
+        self.users
+            .remove_if(&(room_id.to_string(), user_id.clone()), |_, info| {
+                info.id == id
+            });
+    }
+
+    pub fn user(&self, room_id: &str, user_id: &UserId) -> Option<ConnectionInfo> {
+        self.users
+            .get(&(room_id.to_string(), user_id.clone()))
+            .map(|info| info.clone())
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::VecDeque;
+
+use axum::extract::ws::Message as WsMessage;
+use serde::Serialize;
+
+use super::codec::{Codec, CodecError};
+use crate::message_bus::Receiver;
+
+/// Frames kept for redelivery; a client this far behind is cut off
+const MAX_UNACKED: usize = 1024;
+
+/// A routed message as written to the socket, with its sequence number
+#[derive(Serialize)]
+struct Sequenced<'a, T> {
+    seq: u64,
+    #[serde(flatten)]
+    msg: &'a T,
+}
+
+/// Everything a session needs to outlive its socket: the bus channel
+/// messages queue in and the delivery state of what was already sent
+pub struct Mailbox<T> {
+    pub bus_rx: Receiver<T>,
+    pub delivery: Delivery,
+}
+
+/// Per-recipient sequence numbers and, in ack mode, the frames the client
+/// has not confirmed yet. Lives as long as the session, so numbering and
+/// unacked frames survive a resume.
+pub struct Delivery {
+    next_seq: u64,
+    ack_mode: bool,
+    codec: Codec,
+    /// Frames with the codec they were encoded in
+    unacked: VecDeque<(u64, Codec, WsMessage)>,
+    /// A frame the socket failed to take, with its codec unless it is an
+    /// opaque frame. Goes out again on resume, with or without ack mode.
+    unsent: Option<(Option<Codec>, WsMessage)>,
+}
+
+impl Delivery {
+    pub fn new(ack_mode: bool, codec: Codec) -> Self {
+        Self {
+            next_seq: 1,
+            ack_mode,
+            codec,
+            unacked: VecDeque::new(),
+            unsent: None,
+        }
+    }
+
+    /// Adopt the settings of a resumed connection; unacked frames are kept
+    /// for the redelivery
+    pub fn reconnect(&mut self, ack_mode: bool, codec: Codec) {
+        self.ack_mode = ack_mode;
+        self.codec = codec;
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Encode `msg` with the next sequence number, remembering the frame
+    /// until it is acknowledged when in ack mode
+    pub fn frame<T: Serialize>(&mut self, msg: &T) -> Result<WsMessage, CodecError> {
+        let seq = self.next_seq;
+        let frame = self.codec.encode(&Sequenced { seq, msg })?;
+        self.next_seq += 1;
+
+        if self.ack_mode {
+            self.unacked.push_back((seq, self.codec, frame.clone()));
+        }
+        Ok(frame)
+    }
+
+    /// Whether the client stopped acknowledging: no more frames can be
+    /// kept for it without losing some on resume
+    pub fn is_behind(&self) -> bool {
+        self.unacked.len() >= MAX_UNACKED
+    }
+
+    /// Confirm every frame up to and including `seq`
+    pub fn ack(&mut self, seq: u64) {
+        while self
+            .unacked
+            .front()
+            .is_some_and(|(first, _, _)| *first <= seq)
+        {
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Keep a frame the socket failed to take, so a resumed connection
+    /// gets it. `sequenced` frames in ack mode are already kept as unacked.
+    pub fn keep_unsent(&mut self, frame: WsMessage, sequenced: bool) {
+        if sequenced && self.ack_mode {
+            return;
+        }
+        self.unsent = Some((sequenced.then_some(self.codec), frame));
+    }
+
+    /// Frames to send again after a resume, oldest first, re-encoded if
+    /// the client came back with another codec. Call [`Self::redelivered`]
+    /// once they are out.
+    pub fn redelivery(&self) -> Vec<WsMessage> {
+        let unacked = self
+            .unacked
+            .iter()
+            .filter_map(|(seq, codec, frame)| self.reencode(*seq, *codec, frame));
+        let unsent = self.unsent.iter().filter_map(|(codec, frame)| match codec {
+            Some(codec) => self.reencode(0, *codec, frame),
+            None => Some(frame.clone()),
+        });
+        unacked.chain(unsent).collect()
+    }
+
+    /// The redelivery reached the socket. Unacked frames stay until acked,
+    /// unless the client resumed without ack mode and will never ack them.
+    pub fn redelivered(&mut self) {
+        self.unsent = None;
+        if !self.ack_mode {
+            self.unacked.clear();
+        }
+    }
+
+    fn reencode(&self, seq: u64, codec: Codec, frame: &WsMessage) -> Option<WsMessage> {
+        if codec == self.codec {
+            return Some(frame.clone());
+        }
+        let reencoded = codec
+            .decode::<serde_json::Value>(frame)
+            .and_then(|value| self.codec.encode(&value));
+        match reencoded {
+            Ok(frame) => Some(frame),
+            Err(e) => {
+                tracing::error!("Failed to re-encode frame {}: {}", seq, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seq(frame: &WsMessage) -> u64 {
+        let value: serde_json::Value = Codec::Json.decode(frame).unwrap();
+        value["seq"].as_u64().unwrap()
+    }
+
+    #[test]
+    fn keeps_the_unsent_frame_without_ack_mode() {
+        let mut delivery = Delivery::new(false, Codec::Json);
+        delivery.frame(&serde_json::json!({ "n": 1 })).unwrap();
+        let frame = delivery.frame(&serde_json::json!({ "n": 2 })).unwrap();
+        delivery.keep_unsent(frame, true);
+
+        let redelivery = delivery.redelivery();
+        assert_eq!(redelivery.iter().map(seq).collect::<Vec<_>>(), vec![2]);
+
+        delivery.redelivered();
+        assert!(delivery.redelivery().is_empty());
+    }
+
+    #[test]
+    fn redelivers_unacked_frames_in_order() {
+        let mut delivery = Delivery::new(true, Codec::Json);
+        for n in 1..=3 {
+            delivery.frame(&serde_json::json!({ "n": n })).unwrap();
+        }
+        delivery.ack(1);
+        let last = delivery.frame(&serde_json::json!({ "n": 4 })).unwrap();
+        delivery.keep_unsent(last, true);
+
+        delivery.redelivered();
+        let redelivery = delivery.redelivery();
+        assert_eq!(
+            redelivery.iter().map(seq).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn falls_behind_instead_of_forgetting_frames() {
+        let mut delivery = Delivery::new(true, Codec::Json);
+        for n in 0..MAX_UNACKED {
+            assert!(!delivery.is_behind());
+            delivery.frame(&serde_json::json!({ "n": n })).unwrap();
+        }
+        assert!(delivery.is_behind());
+        assert_eq!(seq(&delivery.redelivery()[0]), 1);
+
+        delivery.ack(1);
+        assert!(!delivery.is_behind());
+    }
+
+    #[test]
+    fn reencodes_for_a_new_codec() {
+        let mut delivery = Delivery::new(true, Codec::Json);
+        delivery.frame(&serde_json::json!({ "n": 1 })).unwrap();
+        delivery.reconnect(true, Codec::MessagePack);
+
+        let redelivery = delivery.redelivery();
+        let value: serde_json::Value = Codec::MessagePack.decode(&redelivery[0]).unwrap();
+        assert_eq!(value["n"], 1);
+    }
+
+    #[test]
+    fn forgets_unacked_frames_once_resumed_without_ack_mode() {
+        let mut delivery = Delivery::new(true, Codec::Json);
+        for n in 1..=2 {
+            delivery.frame(&serde_json::json!({ "n": n })).unwrap();
+        }
+
+        delivery.reconnect(false, Codec::Json);
+        let redelivery = delivery.redelivery();
+        assert_eq!(redelivery.iter().map(seq).collect::<Vec<_>>(), vec![1, 2]);
+
+        delivery.redelivered();
+        assert!(delivery.redelivery().is_empty());
+        delivery.frame(&serde_json::json!({ "n": 3 })).unwrap();
+        assert!(delivery.redelivery().is_empty());
+    }
+}
@@ -2,53 +2,291 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use axum::extract::ws::{Message as WsMessage, WebSocket};
-use futures_util::{SinkExt, StreamExt};
+use bytes::Bytes;
+use futures_util::{
+    SinkExt, StreamExt,
+    stream::{SplitSink, SplitStream},
+};
+use serde_json::{Map, Value};
 use tokio::time::{Instant, interval};
 
+use super::{
+    LoopExit, close_oversized,
+    codec::Codec,
+    connections::Peer,
+    delivery::{Delivery, Mailbox},
+    opaque,
+    protocol::{self, FrameRef, Rejection},
+    rate_limit::FloodGuard,
+    send_frames, send_or_return,
+    session::{ParkedHost, SessionRegistry},
+    user,
+};
 use crate::{
     AppState,
     domain::{
-        event::{DisconnectReason, ToHostEvent},
-        message::{HostWebSocketMessage, ToUserMessage},
+        event::{DisconnectReason, ErrorCode},
+        message::{HostWebSocketMessage, MessagePayload, ToHostMessage, ToUserMessage},
+        room::{Room, RoomState},
         user::UserId,
     },
+    metrics::METRICS,
+    storage::HostPresence,
 };
 
 const PING_INTERVAL: Duration = Duration::from_secs(30);
 const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the host is told the spectator count, if it changed
+const SPECTATOR_REPORT_INTERVAL: Duration = Duration::from_secs(5);
 
 pub async fn handle_host_ws(
     socket: WebSocket,
     state: Arc<AppState>,
     room_id: String,
     host_id: UserId,
+    peer: Peer,
+    ack_mode: bool,
+) {
+    let codec = Codec::from_protocol(socket.protocol());
+    let parked = state.sessions.resume_host(&room_id, &host_id);
+
+    let mailbox = match parked {
+        Some(mut parked) => {
+            tracing::info!("Host {} reconnected to room {}", host_id.as_str(), room_id);
+            // Members were only told the host was away if no other host stayed
+            if !has_other_hosts(&state, &room_id, &host_id) {
+                notify_room_users(&state, &room_id, ToUserMessage::host_returned).await;
+            }
+            parked.mailbox.delivery.reconnect(ack_mode, codec);
+            parked.mailbox
+        }
+        None => Mailbox {
+            bus_rx: state.message_bus.register_host(&room_id, &host_id),
+            delivery: Delivery::new(ack_mode, codec),
+        },
+    };
+
+    let (ws_sender, ws_receiver) = socket.split();
+    serve_host(
+        ws_sender,
+        ws_receiver,
+        mailbox,
+        state,
+        room_id,
+        host_id,
+        peer,
+    )
+    .await;
+}
+
+/// Keep serving a member's socket as the host of the room it was promoted
+/// in. Sequence numbers carry on from the member session.
+pub async fn take_over(
+    ws_sender: SplitSink<WebSocket, WsMessage>,
+    ws_receiver: SplitStream<WebSocket>,
+    delivery: Delivery,
+    state: Arc<AppState>,
+    room_id: String,
+    host_id: UserId,
+    peer: Peer,
 ) {
-    let (mut ws_sender, mut ws_receiver) = socket.split();
-    let mut bus_rx = state.message_bus.register_host(&room_id);
+    // The channel parked when the room was handed over holds what the host
+    // was sent since
+    let bus_rx = match state.sessions.resume_host(&room_id, &host_id) {
+        Some(parked) => parked.mailbox.bus_rx,
+        None => state.message_bus.register_host(&room_id, &host_id),
+    };
+    let mailbox = Mailbox { bus_rx, delivery };
+    serve_host(
+        ws_sender,
+        ws_receiver,
+        mailbox,
+        state,
+        room_id,
+        host_id,
+        peer,
+    )
+    .await;
+}
+
+async fn serve_host(
+    mut ws_sender: SplitSink<WebSocket, WsMessage>,
+    ws_receiver: SplitStream<WebSocket>,
+    mut mailbox: Mailbox<ToHostMessage>,
+    state: Arc<AppState>,
+    room_id: String,
+    host_id: UserId,
+    peer: Peer,
+) {
+    // The host gets the current members first, then whatever it did not
+    // acknowledge; Join/Leave events queued while a returning host was
+    // away apply on top of the snapshot idempotently.
+    let codec = mailbox.delivery.codec();
+    let users = state.storage.get_room_users(&room_id);
+    let mut hosts: Vec<UserId> = state
+        .storage
+        .get_host_presence(&room_id)
+        .into_iter()
+        .filter(|(id, presence)| *presence == HostPresence::Connected && *id != host_id)
+        .map(|(id, _)| id)
+        .collect();
+    hosts.push(host_id.clone());
+    let spectators = state.storage.get_spectator_count(&room_id);
+    let snapshot = ToHostMessage::members(host_id.clone(), users, hosts, spectators);
+    let opened = match codec.encode(&snapshot) {
+        Ok(frame) => {
+            let mut frames = vec![frame];
+            frames.extend(mailbox.delivery.redelivery());
+            send_frames(&mut ws_sender, frames).await
+        }
+        Err(e) => {
+            tracing::error!("Failed to serialize members for host: {}", e);
+            false
+        }
+    };
+
+    if opened {
+        mailbox.delivery.redelivered();
+    }
+    METRICS.connected_hosts.inc();
+    let connection = state.connections.open_host(&room_id, &host_id, peer, codec);
+    state
+        .storage
+        .set_host_presence(&room_id, &host_id, Some(HostPresence::Connected));
+    state
+        .message_bus
+        .send_to_host(&room_id, ToHostMessage::host_joined(host_id.clone()))
+        .await;
+    let exit = if opened {
+        run_host_loop(
+            ws_sender,
+            ws_receiver,
+            &mut mailbox,
+            &state,
+            &room_id,
+            &host_id,
+            spectators,
+        )
+        .await
+    } else {
+        LoopExit::Dropped
+    };
+    state.connections.close_host(&room_id, &host_id, connection);
+    METRICS.connected_hosts.dec();
+
+    // The newer connection of a replaced host keeps it in the room
+    if !matches!(exit, LoopExit::Replaced) {
+        state
+            .message_bus
+            .send_to_host(&room_id, ToHostMessage::host_left(host_id.clone()))
+            .await;
+    }
+
+    match exit {
+        _ if was_handed_over(&state, &room_id, &host_id) => {
+            tracing::info!(
+                "Host {} left room {} after losing its host rights",
+                host_id.as_str(),
+                room_id
+            );
+            state.message_bus.unregister_host(&room_id, &host_id);
+            state.storage.set_host_presence(&room_id, &host_id, None);
+        }
+        LoopExit::Dropped
+            if !state.config.host_reconnect_grace.is_zero()
+                && !is_room_closed(&state, &room_id) =>
+        {
+            park_host_session(&state, room_id, host_id, mailbox).await;
+        }
+        LoopExit::Replaced => {
+            tracing::info!(
+                "Host {} connection to room {} replaced by a newer one",
+                host_id.as_str(),
+                room_id
+            );
+        }
+        _ => {
+            // Cleanup
+            cleanup_host_disconnect(&state, &room_id, &host_id).await;
+        }
+    }
+}
+
+async fn run_host_loop(
+    mut ws_sender: SplitSink<WebSocket, WsMessage>,
+    mut ws_receiver: SplitStream<WebSocket>,
+    mailbox: &mut Mailbox<ToHostMessage>,
+    state: &Arc<AppState>,
+    room_id: &str,
+    host_id: &UserId,
+    mut spectators: usize,
+) -> LoopExit {
     let mut ping_interval = interval(PING_INTERVAL);
     ping_interval.tick().await; // consume first immediate tick
+    let mut spectator_interval = interval(SPECTATOR_REPORT_INTERVAL);
+    spectator_interval.tick().await;
     let mut pong_deadline: Option<Instant> = None;
+    let mut frames_received: u64 = 0;
+    let room_type = state
+        .storage
+        .get_room(room_id)
+        .map(|room| room.room_type.as_str().to_string())
+        .unwrap_or_default();
+    let mut guard = FloodGuard::new(
+        state.config.rate_limits.for_room_type(&room_type),
+        state.config.rate_limit_strikes,
+        &room_type,
+        true,
+    );
+    let max_depth = state
+        .config
+        .message_limits
+        .for_room_type(&room_type)
+        .max_depth;
 
     loop {
         tokio::select! {
             // Message from a user via the bus -> forward to host WS
-            msg = bus_rx.recv() => {
+            msg = mailbox.bus_rx.recv() => {
                 match msg {
+                    Some(_) if mailbox.delivery.is_behind() => {
+                        tracing::warn!("Host {} stopped acknowledging messages, disconnecting", host_id.as_str());
+                        METRICS.record_disconnect("host", &DisconnectReason::SlowConsumer);
+                        let msg = ToHostMessage::disconnect(host_id.clone(), DisconnectReason::SlowConsumer);
+                        if let Ok(frame) = mailbox.delivery.codec().encode(&msg) {
+                            let _ = ws_sender.send(frame).await;
+                        }
+                        return LoopExit::Closed;
+                    }
                     Some(msg) => {
+                        // Hosts hear about each other, not about themselves
+                        if msg.is_host_presence() && msg.user_id == *host_id {
+                            continue;
+                        }
+
                         // If this is a disconnect message for the host, break
-                        if matches!(msg.event, ToHostEvent::Disconnect)
-                            && msg.user_id == host_id
+                        if let Some(reason) = msg.disconnect_reason()
+                            && msg.user_id == *host_id
                         {
-                            let json = serde_json::to_string(&msg).unwrap();
-                            let _ = ws_sender.send(WsMessage::Text(json.into())).await;
-                            break;
+                            METRICS.record_disconnect("host", &reason);
+                            if let Ok(frame) = mailbox.delivery.frame(&msg) {
+                                let _ = ws_sender.send(frame).await;
+                            }
+                            return LoopExit::Closed;
                         }
 
-                        match serde_json::to_string(&msg) {
-                            Ok(json) => {
-                                if ws_sender.send(WsMessage::Text(json.into())).await.is_err() {
+                        // Opaque frames go out as they came, without a sequence number
+                        let frame = match &msg.data {
+                            Some(data) => opaque::to_host(&msg.user_id, data)
+                                .ok_or_else(|| "user id too long for an opaque frame".into()),
+                            None => mailbox.delivery.frame(&msg),
+                        };
+                        match frame {
+                            Ok(frame) => {
+                                if let Err(frame) = send_or_return(&mut ws_sender, frame).await {
                                     tracing::error!("Failed to send message to host {}", host_id.as_str());
-                                    break;
+                                    mailbox.delivery.keep_unsent(frame, msg.data.is_none());
+                                    return LoopExit::Dropped;
                                 }
                             }
                             Err(e) => {
@@ -56,9 +294,18 @@ pub async fn handle_host_ws(
                             }
                         }
                     }
+                    None if mailbox.bus_rx.overflowed() => {
+                        tracing::warn!("Host {} fell behind its queue, disconnecting", host_id.as_str());
+                        METRICS.record_disconnect("host", &DisconnectReason::SlowConsumer);
+                        let msg = ToHostMessage::disconnect(host_id.clone(), DisconnectReason::SlowConsumer);
+                        if let Ok(frame) = mailbox.delivery.codec().encode(&msg) {
+                            let _ = ws_sender.send(frame).await;
+                        }
+                        return LoopExit::Closed;
+                    }
                     None => {
-                        // Channel closed
-                        break;
+                        // Channel closed: a newer connection of this host registered
+                        return LoopExit::Replaced;
                     }
                 }
             }
@@ -66,18 +313,60 @@ pub async fn handle_host_ws(
             // Message from host WS -> route to target user
             ws_msg = ws_receiver.next() => {
                 match ws_msg {
-                    Some(Ok(WsMessage::Text(text))) => {
-                        handle_host_message(&state, &room_id, &host_id, &text);
+                    Some(Ok(frame @ (WsMessage::Text(_) | WsMessage::Binary(_)))) => {
+                        frames_received += 1;
+                        let mut strike_out = false;
+                        let handled = match guard.admit(&state.rate_limiter, room_id, host_id) {
+                            Ok(()) => handle_host_frame(state, room_id, host_id, &mut mailbox.delivery, max_depth, &frame).await,
+                            Err(throttled) => {
+                                strike_out = throttled.strike_out;
+                                Err(throttled.rejection)
+                            }
+                        };
+                        if let Err(rejection) = handled {
+                            tracing::warn!("Rejected frame {} from host {}: {}", frames_received, host_id.as_str(), rejection);
+                            let msg = ToHostMessage::error(host_id.clone(), rejection.payload(frames_received));
+                            if let Ok(frame) = mailbox.delivery.codec().encode(&msg)
+                                && ws_sender.send(frame).await.is_err()
+                            {
+                                return LoopExit::Dropped;
+                            }
+                            if rejection.code() == ErrorCode::PayloadTooLarge {
+                                tracing::warn!("Host {} went over the message limits of room {}, disconnecting", host_id.as_str(), room_id);
+                                let msg = ToHostMessage::disconnect(host_id.clone(), DisconnectReason::MessageTooLarge);
+                                close_oversized(&mut ws_sender, mailbox.delivery.codec().encode(&msg), "host", "depth").await;
+                                return LoopExit::Closed;
+                            }
+                            if strike_out {
+                                tracing::warn!("Host {} kept flooding room {}, disconnecting", host_id.as_str(), room_id);
+                                METRICS.record_disconnect("host", &DisconnectReason::RateLimited);
+                                let msg = ToHostMessage::disconnect(host_id.clone(), DisconnectReason::RateLimited);
+                                if let Ok(frame) = mailbox.delivery.codec().encode(&msg) {
+                                    let _ = ws_sender.send(frame).await;
+                                }
+                                return LoopExit::Closed;
+                            }
+                        }
                     }
                     Some(Ok(WsMessage::Pong(_))) => {
                         pong_deadline = None;
                     }
-                    Some(Ok(WsMessage::Close(_))) | None => {
-                        break;
+                    Some(Ok(WsMessage::Close(_))) => {
+                        METRICS.record_disconnect("host", &DisconnectReason::UserClosed);
+                        return LoopExit::Closed;
+                    }
+                    None => {
+                        return LoopExit::Dropped;
+                    }
+                    Some(Err(e)) if protocol::is_too_large(&e) => {
+                        tracing::warn!("Host {} sent a frame over the size limit of room {}: {}", host_id.as_str(), room_id, e);
+                        let msg = ToHostMessage::disconnect(host_id.clone(), DisconnectReason::MessageTooLarge);
+                        close_oversized(&mut ws_sender, mailbox.delivery.codec().encode(&msg), "host", "size").await;
+                        return LoopExit::Closed;
                     }
                     Some(Err(e)) => {
                         tracing::error!("WebSocket error for host {}: {}", host_id.as_str(), e);
-                        break;
+                        return LoopExit::Dropped;
                     }
                     _ => {}
                 }
@@ -88,65 +377,575 @@ pub async fn handle_host_ws(
                 if let Some(deadline) = pong_deadline
                     && Instant::now() > deadline {
                         tracing::warn!("Host {} pong timeout, disconnecting", host_id.as_str());
-                        break;
+                        METRICS.record_pong_timeout("host");
+                        return LoopExit::Dropped;
                     }
                 if ws_sender.send(WsMessage::Ping(vec![].into())).await.is_err() {
-                    break;
+                    return LoopExit::Dropped;
                 }
                 pong_deadline = Some(Instant::now() + PONG_TIMEOUT);
             }
+
+            // Spectators come and go too often to report one by one
+            _ = spectator_interval.tick() => {
+                let count = state.storage.get_spectator_count(room_id);
+                if count == spectators {
+                    continue;
+                }
+                spectators = count;
+                match mailbox.delivery.frame(&ToHostMessage::spectators(host_id.clone(), count)) {
+                    Ok(frame) => {
+                        if let Err(frame) = send_or_return(&mut ws_sender, frame).await {
+                            mailbox.delivery.keep_unsent(frame, true);
+                            return LoopExit::Dropped;
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to serialize spectator count for host: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Act on a data frame from the host, or say why it was refused
+async fn handle_host_frame(
+    state: &Arc<AppState>,
+    room_id: &str,
+    host_id: &UserId,
+    delivery: &mut Delivery,
+    max_depth: usize,
+    frame: &WsMessage,
+) -> Result<(), Rejection> {
+    if let WsMessage::Binary(bytes) = frame
+        && opaque::is_opaque(bytes)
+    {
+        return relay_host_binary(state, room_id, host_id, bytes).await;
+    }
+
+    let (msg, frame_ref) = protocol::decode(delivery.codec(), frame, max_depth)?;
+    match msg {
+        HostWebSocketMessage::Ack { seq } => delivery.ack(seq),
+        HostWebSocketMessage::Message { user_id, message } => {
+            check_member(state, room_id, &user_id, frame_ref)?;
+            state
+                .message_bus
+                .send_to_user(
+                    &user_id,
+                    room_id,
+                    ToUserMessage::message(user_id.clone(), message),
+                )
+                .await;
+        }
+        // Waitlisted users can be kicked too
+        HostWebSocketMessage::Disconnect { user_id } => {
+            if !user::kick_user(state, room_id, &user_id, DisconnectReason::Kicked).await {
+                return Err(not_in_room(&user_id, frame_ref));
+            }
+        }
+        HostWebSocketMessage::Broadcast {
+            user_ids,
+            except_user_ids,
+            message,
+        } => {
+            broadcast_host_message(
+                state,
+                room_id,
+                host_id,
+                user_ids,
+                &except_user_ids,
+                &message,
+            )
+            .await
+        }
+        HostWebSocketMessage::Lock => {
+            change_room_state(state, room_id, host_id, RoomState::Locked).await
+        }
+        HostWebSocketMessage::Unlock => {
+            change_room_state(state, room_id, host_id, RoomState::Open).await
+        }
+        HostWebSocketMessage::Start => {
+            change_room_state(state, room_id, host_id, RoomState::InProgress).await
+        }
+        HostWebSocketMessage::End => {
+            change_room_state(state, room_id, host_id, RoomState::Closed).await
+        }
+        HostWebSocketMessage::Ban {
+            user_id,
+            duration_secs,
+        } => ban_user(state, room_id, host_id, user_id, duration_secs).await,
+        HostWebSocketMessage::Unban { user_id } => {
+            state.storage.update_room(room_id, &mut |room| {
+                room.unban(&user_id);
+            });
+            tracing::info!(
+                "Host {} unbanned user {} from room {}",
+                host_id.as_str(),
+                user_id.as_str(),
+                room_id
+            );
+        }
+        HostWebSocketMessage::SetState { key, value } => {
+            update_shared_state(state, room_id, host_id, &mut |room| {
+                room.set_shared_key(key.clone(), value.clone())
+            })
+            .await
+        }
+        HostWebSocketMessage::PatchState { patch } => {
+            update_shared_state(state, room_id, host_id, &mut |room| {
+                room.patch_shared_state(&patch)
+            })
+            .await
+        }
+        HostWebSocketMessage::DeleteState { key } => {
+            update_shared_state(state, room_id, host_id, &mut |room| {
+                room.set_shared_key(key.clone(), Value::Null)
+            })
+            .await
+        }
+        HostWebSocketMessage::TransferHost { user_id } => {
+            let is_primary = state
+                .storage
+                .get_room(room_id)
+                .is_some_and(|room| room.host_id == *host_id);
+            if !is_primary {
+                return Err(Rejection::new(
+                    ErrorCode::NotEligible,
+                    "only the primary host can hand the room over",
+                    frame_ref,
+                ));
+            }
+            check_member(state, room_id, &user_id, frame_ref.clone())?;
+            let can_host = state
+                .storage
+                .get_room_member(room_id, &user_id)
+                .is_some_and(|member| member.can_host);
+            if !can_host {
+                return Err(Rejection::new(
+                    ErrorCode::NotEligible,
+                    format!("user {} cannot host", user_id.as_str()),
+                    frame_ref,
+                ));
+            }
+            transfer_host(state, room_id, &user_id).await;
+        }
+        HostWebSocketMessage::Unknown => {
+            return Err(Rejection::new(
+                ErrorCode::UnknownEvent,
+                "unknown event",
+                frame_ref,
+            ));
         }
     }
+    Ok(())
+}
 
-    // Cleanup
-    cleanup_host_disconnect(&state, &room_id, &host_id).await;
+/// Host events addressed to one member need that member in the room
+fn check_member(
+    state: &AppState,
+    room_id: &str,
+    user_id: &UserId,
+    frame_ref: FrameRef,
+) -> Result<(), Rejection> {
+    if state.storage.is_user_in_room(room_id, user_id) {
+        return Ok(());
+    }
+    Err(not_in_room(user_id, frame_ref))
 }
 
-fn handle_host_message(state: &AppState, room_id: &str, host_id: &UserId, text: &str) {
-    let msg: HostWebSocketMessage = match serde_json::from_str(text) {
-        Ok(msg) => msg,
-        Err(e) => {
-            tracing::warn!("Invalid message from host {}: {}", host_id.as_str(), e);
-            return;
+fn not_in_room(user_id: &UserId, frame_ref: FrameRef) -> Rejection {
+    Rejection::new(
+        ErrorCode::UserNotInRoom,
+        format!("user {} is not in the room", user_id.as_str()),
+        frame_ref,
+    )
+}
+
+/// Relay an opaque frame to the member named in its header, or to every
+/// member and spectator when the header names none
+async fn relay_host_binary(
+    state: &AppState,
+    room_id: &str,
+    host_id: &UserId,
+    bytes: &Bytes,
+) -> Result<(), Rejection> {
+    let Some((target, data)) = opaque::host_payload(bytes) else {
+        return Err(Rejection::new(
+            ErrorCode::InvalidMessage,
+            "truncated binary frame header",
+            FrameRef::default(),
+        ));
+    };
+
+    let recipients = match target {
+        Some(user_id) => {
+            check_member(state, room_id, &user_id, FrameRef::default())?;
+            vec![user_id]
+        }
+        None => {
+            state
+                .message_bus
+                .send_to_spectators(
+                    room_id,
+                    ToUserMessage::binary(host_id.clone(), data.clone()),
+                )
+                .await;
+            state.storage.get_room_users(room_id)
         }
     };
 
-    let target_user_id = &msg.user_id;
+    for user_id in recipients {
+        state
+            .message_bus
+            .send_to_user(
+                &user_id,
+                room_id,
+                ToUserMessage::binary(user_id.clone(), data.clone()),
+            )
+            .await;
+    }
+    Ok(())
+}
 
-    // Check if target user is in the room
-    if !state.storage.is_user_in_room(room_id, target_user_id) {
-        tracing::warn!(
-            "Host {} tried to send to user {} who is not in room {}",
-            host_id.as_str(),
-            target_user_id.as_str(),
-            room_id
-        );
+/// Bans apply to any user, member or not, so they skip the membership check
+async fn ban_user(
+    state: &AppState,
+    room_id: &str,
+    host_id: &UserId,
+    target_user_id: UserId,
+    duration_secs: Option<u64>,
+) {
+    let duration = duration_secs.map(Duration::from_secs);
+    state.storage.update_room(room_id, &mut |room| {
+        room.ban(target_user_id.clone(), duration);
+    });
+    tracing::info!(
+        "Host {} banned user {} from room {} for {:?}",
+        host_id.as_str(),
+        target_user_id.as_str(),
+        room_id,
+        duration
+    );
+
+    user::kick_user(state, room_id, &target_user_id, DisconnectReason::Banned).await;
+    state
+        .message_bus
+        .disconnect_spectator(room_id, &target_user_id, DisconnectReason::Banned)
+        .await;
+}
+
+/// Fan a message out to every room member, or to `userIds` if given,
+/// minus `exceptUserIds`. Listed users that are not members are skipped.
+/// Messages not addressed to a list of users go into the room history and
+/// to the spectators.
+async fn broadcast_host_message(
+    state: &AppState,
+    room_id: &str,
+    host_id: &UserId,
+    user_ids: Option<Vec<UserId>>,
+    except_user_ids: &[UserId],
+    payload: &MessagePayload,
+) {
+    let recipients: Vec<UserId> = match user_ids {
+        Some(user_ids) => {
+            let members = state.storage.get_room_users(room_id);
+            user_ids
+                .into_iter()
+                .filter(|user_id| members.contains(user_id))
+                .collect()
+        }
+        None => {
+            let members = state.storage.record_broadcast(room_id, payload.clone());
+            state
+                .message_bus
+                .send_to_spectators(
+                    room_id,
+                    ToUserMessage::message(host_id.clone(), payload.clone()),
+                )
+                .await;
+            members
+        }
+    }
+    .into_iter()
+    .filter(|user_id| !except_user_ids.contains(user_id))
+    .collect();
+
+    state
+        .message_bus
+        .broadcast_to_users(room_id, &recipients, payload)
+        .await;
+}
+
+/// Apply a change to the shared state and send the resulting diff to
+/// every member, numbered with the version it brings the state to
+async fn update_shared_state(
+    state: &AppState,
+    room_id: &str,
+    host_id: &UserId,
+    change: &mut (dyn FnMut(&mut Room) -> Map<String, Value> + Send),
+) {
+    let mut diff = Map::new();
+    let mut version = 0;
+    let updated = state.storage.update_room(room_id, &mut |room| {
+        diff = change(room);
+        if !diff.is_empty() {
+            room.state_version += 1;
+        }
+        version = room.state_version;
+    });
+    if updated.is_none() || diff.is_empty() {
         return;
     }
 
-    match msg.event.as_str() {
-        "MESSAGE" => {
-            state.message_bus.send_to_user(
-                target_user_id,
+    tracing::debug!(
+        "Host {} changed shared state of room {}",
+        host_id.as_str(),
+        room_id
+    );
+    notify_room_users(state, room_id, |user_id| {
+        ToUserMessage::state_changed(user_id, version, diff.clone())
+    })
+    .await;
+}
+
+/// Make `new_host_id` the primary host of the room. The previous one is
+/// disconnected with `HostTransferred` and every member is told who hosts
+/// now. Co-hosts stay. A new host that is not connected yet gets the
+/// reconnect grace period to attach. Returns the previous host, or `None`
+/// if the room does not exist.
+pub async fn transfer_host(
+    state: &Arc<AppState>,
+    room_id: &str,
+    new_host_id: &UserId,
+) -> Option<UserId> {
+    let mut previous = None;
+    state.storage.update_room(room_id, &mut |room| {
+        previous = Some(std::mem::replace(&mut room.host_id, new_host_id.clone()));
+        room.co_host_ids.remove(new_host_id);
+    })?;
+    let previous = previous?;
+    if previous == *new_host_id {
+        return Some(previous);
+    }
+
+    tracing::info!(
+        "Room {} handed over from host {} to {}",
+        room_id,
+        previous.as_str(),
+        new_host_id.as_str()
+    );
+
+    dismiss_host(state, room_id, &previous, DisconnectReason::HostTransferred).await;
+    // Parked before the members hear of it, so a member taking over finds
+    // the channel
+    await_host(state, room_id, new_host_id);
+    notify_room_users(state, room_id, |user_id| {
+        ToUserMessage::host_changed(user_id, new_host_id)
+    })
+    .await;
+    Some(previous)
+}
+
+/// Cut a user off as a host of the room. A parked session is dropped so
+/// it cannot close the room later; a live one is disconnected.
+pub async fn dismiss_host(
+    state: &AppState,
+    room_id: &str,
+    host_id: &UserId,
+    reason: DisconnectReason,
+) {
+    if state.sessions.discard_host(room_id, host_id) {
+        state.message_bus.unregister_host(room_id, host_id);
+    } else {
+        state
+            .message_bus
+            .disconnect_host(room_id, host_id, reason)
+            .await;
+    }
+}
+
+async fn change_room_state(state: &AppState, room_id: &str, host_id: &UserId, next: RoomState) {
+    let mut result = Ok(());
+    let updated = state
+        .storage
+        .update_room(room_id, &mut |room| result = room.transition(next));
+
+    match (updated, result) {
+        (Some(_), Ok(())) => {
+            tracing::info!(
+                "Host {} moved room {} to {:?}",
+                host_id.as_str(),
                 room_id,
-                ToUserMessage::message(target_user_id.clone(), msg.message),
+                next
             );
+            state
+                .message_bus
+                .send_to_host(room_id, ToHostMessage::room_state(host_id.clone(), next))
+                .await;
+            notify_room_users(state, room_id, |user_id| {
+                ToUserMessage::room_state(user_id, next)
+            })
+            .await;
+            // Slots freed while the room was locked go to the waitlist now
+            if next == RoomState::Open {
+                for promoted in state.storage.promote_waitlisted(room_id) {
+                    user::announce_promotion(state, room_id, &promoted).await;
+                }
+            }
         }
-        "DISCONNECT" => {
-            // Host kicks user
-            state.message_bus.send_to_user(
-                target_user_id,
+        (Some(_), Err(e)) => {
+            tracing::warn!(
+                "Host {} cannot move room {} from {:?} to {:?}",
+                host_id.as_str(),
                 room_id,
-                ToUserMessage::disconnect(target_user_id.clone(), DisconnectReason::Kicked),
+                e.from,
+                e.to
+            );
+        }
+        (None, _) => {
+            tracing::warn!(
+                "Host {} changed state of missing room {}",
+                host_id.as_str(),
+                room_id
             );
         }
-        other => {
-            tracing::warn!("Unknown event '{}' from host {}", other, host_id.as_str());
+    }
+}
+
+/// Keep the room alive while the host reconnects. User messages queue in
+/// the host channel; if the grace period passes, the room closes as usual.
+async fn park_host_session(
+    state: &Arc<AppState>,
+    room_id: String,
+    host_id: UserId,
+    mailbox: Mailbox<ToHostMessage>,
+) {
+    tracing::info!(
+        "Host {} dropped from room {}, keeping it open for {:?}",
+        host_id.as_str(),
+        room_id,
+        state.config.host_reconnect_grace
+    );
+
+    if !has_other_hosts(state, &room_id, &host_id) {
+        notify_room_users(state, &room_id, ToUserMessage::host_away).await;
+    }
+
+    wait_for_host(state, room_id, host_id, mailbox);
+}
+
+/// Make a host that was handed the room while connected nowhere attach
+/// within the reconnect grace period, as if it had dropped. A member takes
+/// the parked channel over through their own socket, on whichever node.
+fn await_host(state: &Arc<AppState>, room_id: &str, host_id: &UserId) {
+    let present = state
+        .storage
+        .get_host_presence(room_id)
+        .iter()
+        .any(|(id, _)| id == host_id);
+    if present {
+        return;
+    }
+    tracing::info!(
+        "Waiting {:?} for host {} to connect to room {}",
+        state.config.host_reconnect_grace,
+        host_id.as_str(),
+        room_id
+    );
+    let mailbox = Mailbox {
+        bus_rx: state.message_bus.register_host(room_id, host_id),
+        delivery: Delivery::new(false, Codec::default()),
+    };
+    wait_for_host(state, room_id.to_string(), host_id.clone(), mailbox);
+}
+
+/// Park the host's channel and close the room as usual unless the host
+/// connects before the grace period ends
+fn wait_for_host(
+    state: &Arc<AppState>,
+    room_id: String,
+    host_id: UserId,
+    mailbox: Mailbox<ToHostMessage>,
+) {
+    state
+        .storage
+        .set_host_presence(&room_id, &host_id, Some(HostPresence::Away));
+    let token = SessionRegistry::new_token();
+    state.sessions.park_host(
+        &room_id,
+        ParkedHost {
+            host_id: host_id.clone(),
+            token: token.clone(),
+            mailbox,
+        },
+    );
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(state.config.host_reconnect_grace).await;
+
+        if state
+            .sessions
+            .expire_host(&room_id, &host_id, &token)
+            .is_none()
+        {
+            return;
+        }
+        // The host may have attached on another node
+        if is_host_connected(&state, &room_id, &host_id) {
+            state.message_bus.unregister_host(&room_id, &host_id);
+        } else {
+            cleanup_host_disconnect(&state, &room_id, &host_id).await;
         }
+    });
+}
+
+/// Another host of the room is connected or parked on any instance
+fn has_other_hosts(state: &AppState, room_id: &str, host_id: &UserId) -> bool {
+    state
+        .storage
+        .get_host_presence(room_id)
+        .iter()
+        .any(|(id, _)| id != host_id)
+}
+
+fn is_host_connected(state: &AppState, room_id: &str, host_id: &UserId) -> bool {
+    state
+        .storage
+        .get_host_presence(room_id)
+        .iter()
+        .any(|(id, presence)| id == host_id && *presence == HostPresence::Connected)
+}
+
+/// The room lives on without this user as a host
+fn was_handed_over(state: &AppState, room_id: &str, host_id: &UserId) -> bool {
+    state
+        .storage
+        .get_room(room_id)
+        .is_some_and(|room| !room.is_host(host_id))
+}
+
+/// A closed room has nothing left to wait for
+fn is_room_closed(state: &AppState, room_id: &str) -> bool {
+    state
+        .storage
+        .get_room(room_id)
+        .is_none_or(|room| room.state == RoomState::Closed)
+}
+
+async fn notify_room_users(
+    state: &AppState,
+    room_id: &str,
+    message: impl Fn(UserId) -> ToUserMessage,
+) {
+    for user_id in state.storage.get_room_users(room_id) {
+        let msg = message(user_id.clone());
+        state.message_bus.send_to_user(&user_id, room_id, msg).await;
     }
 }
 
-async fn cleanup_host_disconnect(state: &AppState, room_id: &str, host_id: &UserId) {
+async fn cleanup_host_disconnect(state: &Arc<AppState>, room_id: &str, host_id: &UserId) {
     tracing::info!(
         "Host {} disconnected from room {}",
         host_id.as_str(),
@@ -154,14 +953,55 @@ async fn cleanup_host_disconnect(state: &AppState, room_id: &str, host_id: &User
     );
 
     // Unregister host channel
-    state.message_bus.unregister_host(room_id);
+    state.message_bus.unregister_host(room_id, host_id);
+    state.storage.set_host_presence(room_id, host_id, None);
+
+    // The room stays with the remaining hosts
+    if has_other_hosts(state, room_id, host_id) {
+        return;
+    }
+
+    // Rooms of some types live on under one of their members
+    if let Some(successor) = find_successor(state, room_id, host_id) {
+        transfer_host(state, room_id, &successor).await;
+        return;
+    }
 
     // Get all users and disconnect them
     let users = state.storage.clear_room_users(room_id);
     state
         .message_bus
-        .disconnect_room_users(room_id, &users, DisconnectReason::RoomClosed);
+        .disconnect_room_users(room_id, &users, DisconnectReason::RoomClosed)
+        .await;
+    state
+        .message_bus
+        .send_to_spectators(
+            room_id,
+            ToUserMessage::disconnect(host_id.clone(), DisconnectReason::RoomClosed),
+        )
+        .await;
 
     // Remove room
     state.storage.remove_room(room_id);
 }
+
+/// The member with the Host role who joined the room first, if the room
+/// type migrates hosts. Members connected to any node count.
+fn find_successor(state: &AppState, room_id: &str, host_id: &UserId) -> Option<UserId> {
+    let room = state.storage.get_room(room_id)?;
+    if room.state == RoomState::Closed
+        || !state
+            .config
+            .host_migration
+            .contains(room.room_type.as_str())
+    {
+        return None;
+    }
+    state
+        .storage
+        .get_room_members(room_id)
+        .into_iter()
+        .filter(|member| member.can_host && member.user_id != *host_id)
+        .min_by_key(|member| member.joined_at)
+        .map(|member| member.user_id)
+}
@@ -1,31 +1,106 @@
+mod codec;
+mod connections;
+mod delivery;
 mod host;
+mod nesting;
+mod opaque;
+mod protocol;
+mod rate_limit;
+mod session;
+mod spectator;
 mod user;
 
+pub use connections::{ConnectionInfo, ConnectionRegistry};
+pub use host::{dismiss_host, transfer_host};
+
+use connections::Peer;
+pub use protocol::MessageLimits;
+pub use rate_limit::{RateLimit, RateLimiter, ScopeLimits};
+pub use session::SessionRegistry;
+pub use user::kick_user;
+
+use codec::{Codec, CodecError};
+
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use axum::{
-    Extension,
-    extract::{Query, State, WebSocketUpgrade},
+    Extension, Json,
+    extract::{
+        ConnectInfo, Query, State, WebSocketUpgrade,
+        ws::{CloseFrame, Message as WsMessage, WebSocket, close_code},
+    },
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
 use axum_keycloak_auth::decode::KeycloakToken;
+use futures_util::{SinkExt, stream::SplitSink};
 
 use crate::{
     AppState,
-    api::dto::WsQueryParams,
+    api::dto::{JoinRejectedResponse, WsQueryParams},
     auth::{Role, has_role},
-    domain::user::UserId,
+    domain::{
+        event::{DisconnectReason, JoinRejection},
+        message::PROTOCOL_VERSION,
+        user::UserId,
+    },
+    metrics::METRICS,
 };
 
+/// Why a connection loop ended
+enum LoopExit {
+    /// Client closed the socket or was disconnected through the bus
+    Closed,
+    /// Socket dropped without a close frame; the session may be resumed
+    Dropped,
+    /// A newer connection took over the bus channel
+    Replaced,
+    /// The user became the host and keeps the socket as such
+    Promoted,
+}
+
 pub async fn websocket_handler(
     Extension(token): Extension<KeycloakToken<Role>>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
     Query(params): Query<WsQueryParams>,
     State(state): State<Arc<AppState>>,
     ws: WebSocketUpgrade,
-) -> impl IntoResponse {
+) -> Response {
+    // Binary encodings are opt-in; clients that ask for none get JSON
+    let ws = ws.protocols(Codec::PROTOCOLS);
+    let response = upgrade(token, remote_addr, params, state, ws);
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        METRICS.record_upgrade_rejection(response.status().as_u16());
+    }
+    response
+}
+
+fn upgrade(
+    token: KeycloakToken<Role>,
+    remote_addr: SocketAddr,
+    params: WsQueryParams,
+    state: Arc<AppState>,
+    ws: WebSocketUpgrade,
+) -> Response {
     let user_id = UserId::new(&token.subject);
+    let peer = Peer {
+        remote_addr,
+        can_host: has_role(&token, &Role::Host),
+    };
     let room_id_str = params.room_id.clone();
+    let ack = params.ack;
+
+    if let Some(version) = params.protocol_version
+        && version != PROTOCOL_VERSION
+    {
+        tracing::warn!(
+            "User {} requested unsupported protocol version {}",
+            token.subject,
+            version
+        );
+        return (StatusCode::BAD_REQUEST, "Unsupported protocol version").into_response();
+    }
 
     // Validate room exists
     let room = match state.storage.get_room(&room_id_str) {
@@ -36,10 +111,19 @@ pub async fn websocket_handler(
         }
     };
 
+    // The socket refuses oversized frames itself; the loops close the sender
+    let limits = state
+        .config
+        .message_limits
+        .for_room_type(room.room_type.as_str());
+    let ws = ws
+        .max_frame_size(limits.max_frame_bytes)
+        .max_message_size(limits.max_message_bytes);
+
     match params.connection_type.as_str() {
         "host" => {
             // Verify user has host role
-            if !has_role(&token, &Role::Host) {
+            if !peer.can_host {
                 tracing::warn!(
                     "User {} attempted host connection without host role",
                     token.subject
@@ -59,8 +143,10 @@ pub async fn websocket_handler(
 
             tracing::info!("Host {} connecting to room {}", token.subject, room_id_str);
 
-            ws.on_upgrade(move |socket| host::handle_host_ws(socket, state, room_id_str, user_id))
-                .into_response()
+            ws.on_upgrade(move |socket| {
+                host::handle_host_ws(socket, state, room_id_str, user_id, peer, ack)
+            })
+            .into_response()
         }
         "user" => {
             // Verify user has user role
@@ -72,11 +158,123 @@ pub async fn websocket_handler(
                 return (StatusCode::FORBIDDEN, "User role required").into_response();
             }
 
+            if let Err(reason) = room.check_access(&user_id, params.join_secret.as_deref()) {
+                tracing::warn!(
+                    "User {} denied access to room {}: {:?}",
+                    token.subject,
+                    room_id_str,
+                    reason
+                );
+                return reject_join(StatusCode::FORBIDDEN, reason);
+            }
+
+            // Members may come back to a room that no longer accepts joiners
+            let returning = state.storage.is_user_in_room(&room_id_str, &user_id)
+                || params.resume_token.as_deref().is_some_and(|resume_token| {
+                    state
+                        .sessions
+                        .has_parked_user(resume_token, &user_id, &room_id_str)
+                });
+            if !room.state.accepts_joiners() && !returning {
+                tracing::warn!(
+                    "User {} attempted to join room {} in state {:?}",
+                    token.subject,
+                    room_id_str,
+                    room.state
+                );
+                return reject_join(StatusCode::CONFLICT, JoinRejection::RoomNotOpen);
+            }
+
             tracing::info!("User {} connecting to room {}", token.subject, room_id_str);
 
-            ws.on_upgrade(move |socket| user::handle_user_ws(socket, state, room_id_str, user_id))
-                .into_response()
+            let resume_token = params.resume_token;
+            ws.on_upgrade(move |socket| {
+                user::handle_user_ws(socket, state, room_id_str, user_id, peer, resume_token, ack)
+            })
+            .into_response()
+        }
+        "spectator" => {
+            // Verify user has spectator role
+            if !has_role(&token, &Role::Spectator) {
+                tracing::warn!(
+                    "User {} attempted spectator connection without spectator role",
+                    token.subject
+                );
+                return (StatusCode::FORBIDDEN, "Spectator role required").into_response();
+            }
+
+            if let Err(reason) =
+                room.check_spectator_access(&user_id, params.join_secret.as_deref())
+            {
+                tracing::warn!(
+                    "Spectator {} denied access to room {}: {:?}",
+                    token.subject,
+                    room_id_str,
+                    reason
+                );
+                return reject_join(StatusCode::FORBIDDEN, reason);
+            }
+
+            tracing::info!(
+                "Spectator {} connecting to room {}",
+                token.subject,
+                room_id_str
+            );
+
+            ws.on_upgrade(move |socket| {
+                spectator::handle_spectator_ws(socket, state, room_id_str, user_id)
+            })
+            .into_response()
         }
         _ => (StatusCode::BAD_REQUEST, "Invalid connection type").into_response(),
     }
 }
+
+fn reject_join(status: StatusCode, reason: JoinRejection) -> Response {
+    let body = JoinRejectedResponse {
+        error: reason.description(),
+        reason,
+    };
+    (status, Json(body)).into_response()
+}
+
+/// Disconnect a client that went over its message limits: the Disconnect
+/// event `notice`, then a close frame with the same reason
+async fn close_oversized(
+    ws_sender: &mut SplitSink<WebSocket, WsMessage>,
+    notice: Result<WsMessage, CodecError>,
+    role: &str,
+    limit: &str,
+) {
+    METRICS.record_oversized(role, limit);
+    if let Ok(frame) = notice {
+        let _ = ws_sender.send(frame).await;
+    }
+    let close = CloseFrame {
+        code: close_code::SIZE,
+        reason: format!("{:?}", DisconnectReason::MessageTooLarge).into(),
+    };
+    let _ = ws_sender.send(WsMessage::Close(Some(close))).await;
+}
+
+/// Write frames in order; false if the socket is gone
+async fn send_frames(
+    ws_sender: &mut SplitSink<WebSocket, WsMessage>,
+    frames: Vec<WsMessage>,
+) -> bool {
+    for frame in frames {
+        if ws_sender.send(frame).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Write one frame, handing it back if the socket is gone so a resumed
+/// connection can still get it
+async fn send_or_return(
+    ws_sender: &mut SplitSink<WebSocket, WsMessage>,
+    frame: WsMessage,
+) -> Result<(), WsMessage> {
+    ws_sender.send(frame.clone()).await.map_err(|_| frame)
+}
@@ -0,0 +1,319 @@
+use super::codec::Codec;
+
+/// Whether an encoded frame nests arrays and maps deeper than `max_depth`,
+/// the frame itself included. Reads the encoding without decoding it, so
+/// deep frames are refused before the decoder recurses into them. Frames
+/// that are malformed or cut short are left to the decoder to reject.
+pub fn exceeds(codec: Codec, bytes: &[u8], max_depth: usize) -> bool {
+    match codec {
+        Codec::Json => json_exceeds(bytes, max_depth),
+        Codec::MessagePack => Walk::new(bytes, max_depth).msgpack(),
+        Codec::Cbor => Walk::new(bytes, max_depth).cbor(),
+    }
+}
+
+fn json_exceeds(bytes: &[u8], max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in bytes {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Items still expected by an open array or map; `None` until a CBOR break
+struct Open {
+    remaining: Option<u64>,
+    /// Indefinite CBOR strings are chunked but do not nest
+    nests: bool,
+}
+
+struct Walk<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    open: Vec<Open>,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl<'a> Walk<'a> {
+    fn new(bytes: &'a [u8], max_depth: usize) -> Self {
+        Self {
+            bytes,
+            pos: 0,
+            open: Vec::new(),
+            depth: 0,
+            max_depth,
+        }
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    /// Big-endian unsigned integer of `len` bytes
+    fn uint(&mut self, len: usize) -> Option<u64> {
+        let bytes = self.bytes.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes.iter().fold(0, |n, &b| (n << 8) | u64::from(b)))
+    }
+
+    fn skip(&mut self, len: u64) -> Option<()> {
+        self.pos = self.pos.checked_add(usize::try_from(len).ok()?)?;
+        (self.pos <= self.bytes.len()).then_some(())
+    }
+
+    /// Open a container of `items` items (`None` for indefinite); true
+    /// once nesting goes past the limit
+    fn open(&mut self, items: Option<u64>, nests: bool) -> bool {
+        if nests {
+            self.depth += 1;
+            if self.depth > self.max_depth {
+                return true;
+            }
+        }
+        self.open.push(Open {
+            remaining: items,
+            nests,
+        });
+        self.settle();
+        false
+    }
+
+    /// Count a finished item against its container, closing containers
+    /// that are now complete
+    fn item_done(&mut self) {
+        if let Some(Open {
+            remaining: Some(remaining),
+            ..
+        }) = self.open.last_mut()
+        {
+            *remaining = remaining.saturating_sub(1);
+        }
+        self.settle();
+    }
+
+    fn settle(&mut self) {
+        while let Some(Open {
+            remaining: Some(0), ..
+        }) = self.open.last()
+        {
+            self.close();
+            if let Some(Open {
+                remaining: Some(remaining),
+                ..
+            }) = self.open.last_mut()
+            {
+                *remaining = remaining.saturating_sub(1);
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        if self.open.pop().is_some_and(|open| open.nests) {
+            self.depth -= 1;
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn msgpack(mut self) -> bool {
+        while !self.finished() {
+            let Some(exceeded) = self.msgpack_item() else {
+                return false;
+            };
+            if exceeded {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Read one header and whatever scalar payload follows it
+    fn msgpack_item(&mut self) -> Option<bool> {
+        let byte = self.byte()?;
+        let container = match byte {
+            0x80..=0x8f => Some(2 * u64::from(byte & 0x0f)),
+            0x90..=0x9f => Some(u64::from(byte & 0x0f)),
+            0xdc => Some(self.uint(2)?),
+            0xdd => Some(self.uint(4)?),
+            0xde => Some(2 * self.uint(2)?),
+            0xdf => Some(2 * self.uint(4)?),
+            _ => None,
+        };
+        if let Some(items) = container {
+            return Some(self.open(Some(items), true));
+        }
+        let payload = match byte {
+            0xa0..=0xbf => u64::from(byte & 0x1f),
+            0xc4 | 0xd9 => self.uint(1)?,
+            0xc5 | 0xda => self.uint(2)?,
+            0xc6 | 0xdb => self.uint(4)?,
+            0xc7 => self.uint(1)? + 1,
+            0xc8 => self.uint(2)? + 1,
+            0xc9 => self.uint(4)? + 1,
+            0xca => 4,
+            0xcb => 8,
+            0xcc | 0xd0 => 1,
+            0xcd | 0xd1 => 2,
+            0xce | 0xd2 => 4,
+            0xcf | 0xd3 => 8,
+            0xd4 => 2,
+            0xd5 => 3,
+            0xd6 => 5,
+            0xd7 => 9,
+            0xd8 => 17,
+            0xc1 => return None,
+            _ => 0,
+        };
+        self.skip(payload)?;
+        self.item_done();
+        Some(false)
+    }
+
+    fn cbor(mut self) -> bool {
+        while !self.finished() {
+            let Some(exceeded) = self.cbor_item() else {
+                return false;
+            };
+            if exceeded {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Read one header and whatever scalar payload follows it
+    fn cbor_item(&mut self) -> Option<bool> {
+        let byte = self.byte()?;
+        let (major, info) = (byte >> 5, byte & 0x1f);
+        let argument = match info {
+            0..=23 => Some(u64::from(info)),
+            24 => Some(self.uint(1)?),
+            25 => Some(self.uint(2)?),
+            26 => Some(self.uint(4)?),
+            27 => Some(self.uint(8)?),
+            31 => None,
+            _ => return None,
+        };
+        match (major, argument) {
+            // Chunks of an indefinite string up to its break
+            (2 | 3, None) => Some(self.open(None, false)),
+            (2 | 3, Some(len)) => {
+                self.skip(len)?;
+                self.item_done();
+                Some(false)
+            }
+            (4, items) => Some(self.open(items, true)),
+            (5, pairs) => Some(self.open(pairs.map(|n| n.saturating_mul(2)), true)),
+            // A tag belongs to the item after it
+            (6, Some(_)) => Some(false),
+            (7, None) => {
+                if !matches!(
+                    self.open.last(),
+                    Some(Open {
+                        remaining: None,
+                        ..
+                    })
+                ) {
+                    return None;
+                }
+                self.close();
+                self.item_done();
+                Some(false)
+            }
+            (0 | 1 | 7, Some(_)) => {
+                self.item_done();
+                Some(false)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn encoded(codec: Codec, value: &serde_json::Value) -> Vec<u8> {
+        match codec {
+            Codec::Json => serde_json::to_vec(value).unwrap(),
+            Codec::MessagePack => rmp_serde::to_vec(value).unwrap(),
+            Codec::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(value, &mut buffer).unwrap();
+                buffer
+            }
+        }
+    }
+
+    #[test]
+    fn counts_arrays_and_maps_in_every_codec() {
+        // Depth 4: the frame, "a", the object in it and "b"
+        let value = json!({ "a": [1, "[[[", { "b": [], "c": { "d": 2.5 } }], "e": -3 });
+        for codec in [Codec::Json, Codec::MessagePack, Codec::Cbor] {
+            let bytes = encoded(codec, &value);
+            assert!(!exceeds(codec, &bytes, 4), "{}", codec.name());
+            assert!(exceeds(codec, &bytes, 3), "{}", codec.name());
+        }
+    }
+
+    #[test]
+    fn siblings_do_not_add_up() {
+        let value = json!([[1], [2], [[3]], {}, []]);
+        for codec in [Codec::Json, Codec::MessagePack, Codec::Cbor] {
+            assert!(
+                !exceeds(codec, &encoded(codec, &value), 3),
+                "{}",
+                codec.name()
+            );
+        }
+    }
+
+    #[test]
+    fn stops_at_the_limit_without_reading_on() {
+        // One-element arrays that claim more frames than there are
+        for (codec, array) in [(Codec::MessagePack, 0x91), (Codec::Cbor, 0x81)] {
+            let bytes = vec![array; 100_000];
+            assert!(exceeds(codec, &bytes, 32), "{}", codec.name());
+        }
+        assert!(exceeds(Codec::Json, "[".repeat(100_000).as_bytes(), 32));
+    }
+
+    #[test]
+    fn indefinite_cbor_strings_do_not_nest() {
+        // [_ "ab" "c"] inside a one-element array
+        let bytes = [0x81, 0x7f, 0x62, b'a', b'b', 0x61, b'c', 0xff];
+        assert!(!exceeds(Codec::Cbor, &bytes, 1));
+        // [_ [1]] inside a one-element array
+        let bytes = [0x81, 0x9f, 0x81, 0x01, 0xff];
+        assert!(exceeds(Codec::Cbor, &bytes, 2));
+        assert!(!exceeds(Codec::Cbor, &bytes, 3));
+    }
+}
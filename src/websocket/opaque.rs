@@ -0,0 +1,96 @@
+use axum::extract::ws::Message as WsMessage;
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::domain::user::UserId;
+
+/// First byte of an opaque binary frame. Encoded events are maps, and no
+/// map starts with it: JSON text cannot start with 0xFF, MessagePack reads
+/// it as the integer -1 and CBOR as a stray "break" that starts no item.
+const TAG: u8 = 0xFF;
+
+/// Whether a binary frame is opaque passthrough rather than an encoded event
+pub fn is_opaque(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&TAG)
+}
+
+/// Payload of a user frame: `[TAG] payload`
+pub fn user_payload(bytes: &Bytes) -> Bytes {
+    bytes.slice(1..)
+}
+
+/// Target and payload of a host frame: `[TAG][len][userId] payload`.
+/// An empty user id addresses every member. `None` if the header is cut short.
+pub fn host_payload(bytes: &Bytes) -> Option<(Option<UserId>, Bytes)> {
+    let len = usize::from(*bytes.get(1)?);
+    let id = bytes.get(2..2 + len)?;
+    let target = match len {
+        0 => None,
+        _ => Some(UserId::new(std::str::from_utf8(id).ok()?)),
+    };
+    Some((target, bytes.slice(2 + len..)))
+}
+
+/// Frame delivered to a host, prefixed with the sending user like host
+/// frames. `None` for user ids that do not fit the length byte.
+pub fn to_host(user_id: &UserId, payload: &Bytes) -> Option<WsMessage> {
+    let id = user_id.as_str().as_bytes();
+    let len = u8::try_from(id.len()).ok()?;
+    let mut frame = BytesMut::with_capacity(2 + id.len() + payload.len());
+    frame.put_u8(TAG);
+    frame.put_u8(len);
+    frame.put_slice(id);
+    frame.put_slice(payload);
+    Some(WsMessage::Binary(frame.freeze()))
+}
+
+/// Frame delivered to a user
+pub fn to_user(payload: &Bytes) -> WsMessage {
+    let mut frame = BytesMut::with_capacity(1 + payload.len());
+    frame.put_u8(TAG);
+    frame.put_slice(payload);
+    WsMessage::Binary(frame.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{domain::message::ToUserMessage, websocket::codec::Codec};
+
+    #[test]
+    fn encoded_events_are_never_opaque() {
+        let msg = ToUserMessage::message(UserId::new("alice"), serde_json::json!({ "n": 1 }));
+        for codec in [Codec::Json, Codec::MessagePack, Codec::Cbor] {
+            let WsMessage::Binary(bytes) = codec.encode(&msg).unwrap() else {
+                continue;
+            };
+            assert!(
+                !is_opaque(&bytes),
+                "{} frame taken for opaque",
+                codec.name()
+            );
+        }
+    }
+
+    #[test]
+    fn host_frames_carry_the_target() {
+        let frame = Bytes::from_static(b"\xff\x05alicepayload");
+        assert!(is_opaque(&frame));
+        let (target, payload) = host_payload(&frame).unwrap();
+        assert_eq!(target, Some(UserId::new("alice")));
+        assert_eq!(payload, Bytes::from_static(b"payload"));
+
+        let broadcast = Bytes::from_static(b"\xff\x00payload");
+        assert_eq!(host_payload(&broadcast).unwrap().0, None);
+        assert!(host_payload(&Bytes::from_static(b"\xff\x05al")).is_none());
+    }
+
+    #[test]
+    fn frames_to_host_name_the_sender() {
+        let payload = Bytes::from_static(b"payload");
+        let WsMessage::Binary(frame) = to_host(&UserId::new("bob"), &payload).unwrap() else {
+            panic!("opaque frames are binary");
+        };
+        assert_eq!(&frame[..], b"\xff\x03bobpayload");
+        assert!(to_host(&UserId::new("x".repeat(256)), &payload).is_none());
+    }
+}
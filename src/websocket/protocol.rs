@@ -0,0 +1,175 @@
+use std::error::Error;
+use std::fmt;
+
+use axum::extract::ws::Message as WsMessage;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::{
+    codec::{self, Codec},
+    nesting,
+};
+use crate::domain::event::ErrorCode;
+
+/// Size and nesting limits of inbound frames for one kind of room. The
+/// sizes are enforced by the socket itself.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageLimits {
+    pub max_frame_bytes: usize,
+    pub max_message_bytes: usize,
+    /// Arrays and objects nested in one another, the frame itself included
+    pub max_depth: usize,
+}
+
+/// What an `Error` event points back to: the event name and the client's
+/// own `ref`, when the rejected frame had them
+#[derive(Debug, Clone, Default)]
+pub struct FrameRef {
+    event: Option<String>,
+    reference: Option<Value>,
+}
+
+/// A frame the server refused to act on
+#[derive(Debug)]
+pub struct Rejection {
+    code: ErrorCode,
+    detail: String,
+    frame: FrameRef,
+}
+
+impl Rejection {
+    pub fn new(code: ErrorCode, detail: impl Into<String>, frame: FrameRef) -> Self {
+        Self {
+            code,
+            detail: detail.into(),
+            frame,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// `message` of the `Error` event. `index` is the 1-based position of
+    /// the rejected frame among the data frames of the connection.
+    pub fn payload(&self, index: u64) -> Value {
+        let mut payload = serde_json::json!({
+            "code": self.code,
+            "detail": self.detail,
+            "frame": index,
+        });
+        if let Some(event) = &self.frame.event {
+            payload["event"] = event.as_str().into();
+        }
+        if let Some(reference) = &self.frame.reference {
+            payload["ref"] = reference.clone();
+        }
+        payload
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.detail)
+    }
+}
+
+/// Decode an inbound event, keeping what identifies the frame for errors
+/// about it later on. Frames nested deeper than `max_depth`, or deeper
+/// than the decoder itself goes, are refused as too large before decoding.
+pub fn decode<T: DeserializeOwned>(
+    codec: Codec,
+    frame: &WsMessage,
+    max_depth: usize,
+) -> Result<(T, FrameRef), Rejection> {
+    let too_deep = match frame {
+        WsMessage::Text(text) => nesting::exceeds(Codec::Json, text.as_bytes(), max_depth),
+        WsMessage::Binary(bytes) => nesting::exceeds(codec, bytes, max_depth),
+        _ => false,
+    };
+    if too_deep {
+        return Err(Rejection::new(
+            ErrorCode::PayloadTooLarge,
+            format!("nesting deeper than {max_depth}"),
+            FrameRef::default(),
+        ));
+    }
+
+    let value: Value = codec.decode(frame).map_err(|e| {
+        let code = if codec::is_too_deep(&e) {
+            ErrorCode::PayloadTooLarge
+        } else {
+            ErrorCode::InvalidJson
+        };
+        Rejection::new(code, e.to_string(), FrameRef::default())
+    })?;
+
+    let frame_ref = FrameRef {
+        event: value
+            .get("event")
+            .and_then(Value::as_str)
+            .map(str::to_owned),
+        reference: value.get("ref").cloned(),
+    };
+    match serde_json::from_value(value) {
+        Ok(msg) => Ok((msg, frame_ref)),
+        Err(e) => Err(Rejection::new(
+            ErrorCode::InvalidMessage,
+            e.to_string(),
+            frame_ref,
+        )),
+    }
+}
+
+/// Whether a socket error means the client went over the frame or message
+/// size limit
+pub fn is_too_large(error: &axum::Error) -> bool {
+    error
+        .source()
+        .and_then(|source| source.downcast_ref::<tungstenite::Error>())
+        .is_some_and(|error| matches!(error, tungstenite::Error::Capacity(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::message::UserWebSocketMessage;
+
+    fn nested(depth: usize) -> WsMessage {
+        let json = format!(
+            r#"{{"event":"MESSAGE","message":{}{}}}"#,
+            "[".repeat(depth),
+            "]".repeat(depth)
+        );
+        WsMessage::Text(json.into())
+    }
+
+    #[test]
+    fn deep_frames_are_too_large() {
+        assert!(decode::<Value>(Codec::Json, &nested(31), 32).is_ok());
+        let rejection = decode::<Value>(Codec::Json, &nested(40), 32).unwrap_err();
+        assert_eq!(rejection.code(), ErrorCode::PayloadTooLarge);
+    }
+
+    #[test]
+    fn frames_past_the_decoder_limit_are_too_large() {
+        // serde_json gives up at 128 levels, below this limit
+        let rejection = decode::<Value>(Codec::Json, &nested(200), 1000).unwrap_err();
+        assert_eq!(rejection.code(), ErrorCode::PayloadTooLarge);
+
+        let garbage = WsMessage::Text("{".into());
+        let rejection = decode::<Value>(Codec::Json, &garbage, 32).unwrap_err();
+        assert_eq!(rejection.code(), ErrorCode::InvalidJson);
+    }
+
+    #[test]
+    fn rejections_point_back_at_the_frame() {
+        let frame = WsMessage::Text(r#"{"event":"ACK","ref":7}"#.into());
+        let rejection = decode::<UserWebSocketMessage>(Codec::Json, &frame, 32);
+        let payload = rejection.unwrap_err().payload(3);
+        assert_eq!(payload["code"], "InvalidMessage");
+        assert_eq!(payload["frame"], 3);
+        assert_eq!(payload["event"], "ACK");
+        assert_eq!(payload["ref"], 7);
+    }
+}
@@ -0,0 +1,301 @@
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+use super::protocol::{FrameRef, Rejection};
+use crate::domain::{event::ErrorCode, user::UserId};
+
+/// Sustained frames per second and how many may arrive at once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+/// Rate limits of one kind of room; `None` leaves a scope unlimited
+#[derive(Debug, Clone, Copy)]
+pub struct ScopeLimits {
+    /// Each user connection
+    pub connection: Option<RateLimit>,
+    /// Each host connection
+    pub host: Option<RateLimit>,
+    /// Each user across their connections to rooms of the type
+    pub user: Option<RateLimit>,
+    /// Frames from all users of a room together
+    pub room: Option<RateLimit>,
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_sec).min(self.limit.burst);
+        self.updated = now;
+    }
+
+    fn try_take(&mut self) -> bool {
+        if !self.has_token() {
+            return false;
+        }
+        self.take();
+        true
+    }
+
+    fn has_token(&mut self) -> bool {
+        self.refill();
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    /// A full bucket behaves like a new one and can be forgotten
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.limit.burst
+    }
+}
+
+/// Buckets shared by every connection of a user or a room
+#[derive(Default)]
+pub struct RateLimiter {
+    users: DashMap<(String, UserId), TokenBucket>,
+    rooms: DashMap<String, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drop buckets that have been idle long enough to refill
+    pub fn prune(&self) {
+        self.users.retain(|_, bucket| !bucket.is_full());
+        self.rooms.retain(|_, bucket| !bucket.is_full());
+    }
+}
+
+/// Flood protection of a single connection
+pub struct FloodGuard {
+    limits: ScopeLimits,
+    room_type: String,
+    /// Whether frames also count against the user and room scopes
+    shared_scopes: bool,
+    connection: Option<TokenBucket>,
+    strikes: TokenBucket,
+}
+
+impl FloodGuard {
+    pub fn new(limits: ScopeLimits, strikes: u32, room_type: &str, is_host: bool) -> Self {
+        let connection = if is_host {
+            limits.host
+        } else {
+            limits.connection
+        };
+        Self::with_scopes(limits, strikes, room_type, connection, !is_host)
+    }
+
+    /// Spectators send nothing the room accepts, so their frames count only
+    /// against their own connection and never use up a member's budget
+    pub fn spectator(limits: ScopeLimits, strikes: u32) -> Self {
+        Self::with_scopes(limits, strikes, "", limits.connection, false)
+    }
+
+    fn with_scopes(
+        limits: ScopeLimits,
+        strikes: u32,
+        room_type: &str,
+        connection: Option<RateLimit>,
+        shared_scopes: bool,
+    ) -> Self {
+        Self {
+            limits,
+            room_type: room_type.to_string(),
+            shared_scopes,
+            connection: connection.map(TokenBucket::new),
+            strikes: TokenBucket::new(RateLimit {
+                per_sec: 1.0,
+                burst: f64::from(strikes),
+            }),
+        }
+    }
+
+    /// Take a token for one inbound frame from every bucket it counts
+    /// against, or from none of them if one is empty. Host and spectator
+    /// frames count only against their own connection: the user and room
+    /// scopes are for members.
+    pub fn admit(
+        &mut self,
+        limiter: &RateLimiter,
+        room_id: &str,
+        user_id: &UserId,
+    ) -> Result<(), Throttled> {
+        let mut user = self
+            .limits
+            .user
+            .filter(|_| self.shared_scopes)
+            .map(|limit| {
+                limiter
+                    .users
+                    .entry((self.room_type.clone(), user_id.clone()))
+                    .or_insert_with(|| TokenBucket::new(limit))
+            });
+        let mut room = self
+            .limits
+            .room
+            .filter(|_| self.shared_scopes)
+            .map(|limit| {
+                limiter
+                    .rooms
+                    .entry(room_id.to_string())
+                    .or_insert_with(|| TokenBucket::new(limit))
+            });
+
+        let empty = if self.connection.as_mut().is_some_and(|b| !b.has_token()) {
+            Some("connection")
+        } else if user.as_mut().is_some_and(|b| !b.has_token()) {
+            Some("user")
+        } else if room.as_mut().is_some_and(|b| !b.has_token()) {
+            Some("room")
+        } else {
+            None
+        };
+        let Some(scope) = empty else {
+            self.connection.iter_mut().for_each(TokenBucket::take);
+            user.iter_mut().for_each(|bucket| bucket.take());
+            room.iter_mut().for_each(|bucket| bucket.take());
+            return Ok(());
+        };
+
+        // A room flooded by everyone together is no one sender's fault
+        let strike = scope != "room";
+        Err(Throttled {
+            rejection: rate_limited(scope),
+            strike_out: strike && !self.strikes.try_take(),
+        })
+    }
+}
+
+/// A frame over a rate limit
+pub struct Throttled {
+    pub rejection: Rejection,
+    /// The sender kept going over its own limits and is past tolerance
+    pub strike_out: bool,
+}
+
+fn rate_limited(scope: &str) -> Rejection {
+    Rejection::new(
+        ErrorCode::RateLimited,
+        format!("{scope} rate limit exceeded"),
+        FrameRef::default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        per_sec: 2.0,
+        burst: 3.0,
+    };
+
+    fn limits(
+        connection: Option<RateLimit>,
+        user: Option<RateLimit>,
+        room: Option<RateLimit>,
+    ) -> ScopeLimits {
+        ScopeLimits {
+            connection,
+            host: None,
+            user,
+            room,
+        }
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills_at_the_rate() {
+        let mut bucket = TokenBucket::new(LIMIT);
+        assert!((0..3).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+        assert!(!bucket.is_full());
+
+        bucket.updated -= Duration::from_millis(500);
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        // Idle time beyond the burst is not saved up
+        bucket.updated -= Duration::from_secs(60);
+        assert!(bucket.is_full());
+        assert!((0..3).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn user_scope_is_shared_between_connections() {
+        let limiter = RateLimiter::new();
+        let user = UserId::new("user");
+        let mut first = FloodGuard::new(limits(None, Some(LIMIT), None), 1, "game", false);
+        let mut second = FloodGuard::new(limits(None, Some(LIMIT), None), 1, "game", false);
+
+        assert!(first.admit(&limiter, "room-1", &user).is_ok());
+        assert!(second.admit(&limiter, "room-2", &user).is_ok());
+        assert!(first.admit(&limiter, "room-1", &user).is_ok());
+        let throttled = second.admit(&limiter, "room-2", &user).unwrap_err();
+        assert_eq!(throttled.rejection.code(), ErrorCode::RateLimited);
+        assert!(!throttled.strike_out);
+        assert!(
+            second
+                .admit(&limiter, "room-2", &user)
+                .unwrap_err()
+                .strike_out
+        );
+    }
+
+    #[test]
+    fn a_full_room_costs_its_members_nothing() {
+        let limiter = RateLimiter::new();
+        let (flooder, bystander) = (UserId::new("flooder"), UserId::new("bystander"));
+        let room_limit = limits(Some(LIMIT), None, Some(LIMIT));
+        let mut flooding = FloodGuard::new(room_limit, 1, "game", false);
+        let mut bystanding = FloodGuard::new(room_limit, 1, "game", false);
+
+        assert!((0..3).all(|_| flooding.admit(&limiter, "room", &flooder).is_ok()));
+        for _ in 0..5 {
+            let throttled = bystanding.admit(&limiter, "room", &bystander).unwrap_err();
+            assert!(!throttled.strike_out);
+        }
+
+        // Frames the room refused took no tokens from the bystander
+        limiter.rooms.clear();
+        assert!((0..3).all(|_| bystanding.admit(&limiter, "room", &bystander).is_ok()));
+    }
+
+    #[test]
+    fn spectators_use_only_their_connection() {
+        let limiter = RateLimiter::new();
+        let user = UserId::new("user");
+        let mut guard = FloodGuard::spectator(limits(Some(LIMIT), Some(LIMIT), Some(LIMIT)), 1);
+
+        assert!((0..3).all(|_| guard.admit(&limiter, "room", &user).is_ok()));
+        assert!(!guard.admit(&limiter, "room", &user).unwrap_err().strike_out);
+        assert!(guard.admit(&limiter, "room", &user).unwrap_err().strike_out);
+        assert!(limiter.users.is_empty() && limiter.rooms.is_empty());
+    }
+}
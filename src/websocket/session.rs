@@ -0,0 +1,118 @@
+use dashmap::DashMap;
+use uuid::Uuid;
+
+use super::delivery::Mailbox;
+use crate::domain::{
+    event::DisconnectReason,
+    message::{ToHostMessage, ToUserMessage},
+    user::UserId,
+};
+
+/// A user connection that dropped without a close frame. Its bus channel
+/// stays registered, so messages keep buffering until it is resumed.
+pub struct ParkedUser {
+    pub user_id: UserId,
+    pub room_id: String,
+    pub mailbox: Mailbox<ToUserMessage>,
+}
+
+/// A host connection that dropped without a close frame. The room stays
+/// alive and user messages queue in its bus channel.
+pub struct ParkedHost {
+    pub host_id: UserId,
+    /// Identifies this particular drop, so a stale grace timer cannot
+    /// close the room after the host came back and dropped again.
+    pub token: String,
+    pub mailbox: Mailbox<ToHostMessage>,
+}
+
+/// Dropped connections waiting for a reconnect: users by resume token,
+/// hosts by room and host id.
+#[derive(Default)]
+pub struct SessionRegistry {
+    users: DashMap<String, ParkedUser>,
+    hosts: DashMap<(String, UserId), ParkedHost>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn new_token() -> String {
+        Uuid::new_v4().to_string()
+    }
+
+    pub fn park_user(&self, token: String, parked: ParkedUser) {
+        self.users.insert(token, parked);
+    }
+
+    pub fn has_parked_user(&self, token: &str, user_id: &UserId, room_id: &str) -> bool {
+        self.users
+            .get(token)
+            .is_some_and(|parked| parked.user_id == *user_id && parked.room_id == room_id)
+    }
+
+    /// Take a parked session if the token belongs to this user and room.
+    /// A session a newer connection replaced cannot be resumed; its grace
+    /// timer drops it.
+    pub fn resume_user(&self, token: &str, user_id: &UserId, room_id: &str) -> Option<ParkedUser> {
+        self.users
+            .remove_if(token, |_, parked| {
+                parked.user_id == *user_id
+                    && parked.room_id == room_id
+                    && !is_replaced(&parked.mailbox)
+            })
+            .map(|(_, parked)| parked)
+    }
+
+    /// Forget the parked sessions of a user that connected anew, so their
+    /// tokens stop working and their grace timers find nothing to clean up.
+    pub fn discard_user(&self, user_id: &UserId, room_id: &str) {
+        self.users
+            .retain(|_, parked| parked.user_id != *user_id || parked.room_id != room_id);
+    }
+
+    /// Take a parked session regardless of owner, used when the grace window expires.
+    pub fn expire_user(&self, token: &str) -> Option<ParkedUser> {
+        self.users.remove(token).map(|(_, parked)| parked)
+    }
+
+    pub fn park_host(&self, room_id: &str, parked: ParkedHost) {
+        self.hosts
+            .insert((room_id.to_string(), parked.host_id.clone()), parked);
+    }
+
+    /// Take the parked channel of this host in a room.
+    pub fn resume_host(&self, room_id: &str, host_id: &UserId) -> Option<ParkedHost> {
+        self.hosts
+            .remove(&(room_id.to_string(), host_id.clone()))
+            .map(|(_, parked)| parked)
+    }
+
+    /// Forget a parked host, so its grace timer finds nothing to close.
+    /// Returns whether the host was parked.
+    pub fn discard_host(&self, room_id: &str, host_id: &UserId) -> bool {
+        self.hosts
+            .remove(&(room_id.to_string(), host_id.clone()))
+            .is_some()
+    }
+
+    /// Take the parked channel of a host if it is still the given drop.
+    pub fn expire_host(&self, room_id: &str, host_id: &UserId, token: &str) -> Option<ParkedHost> {
+        self.hosts
+            .remove_if(&(room_id.to_string(), host_id.clone()), |_, parked| {
+                parked.token == token
+            })
+            .map(|(_, parked)| parked)
+    }
+}
+
+/// Whether a newer connection took the user's bus channel over, here or,
+/// under the mesh bus, on another node
+fn is_replaced(mailbox: &Mailbox<ToUserMessage>) -> bool {
+    mailbox.bus_rx.is_closed()
+        || mailbox
+            .bus_rx
+            .any_queued(|msg| msg.disconnect_reason() == Some(DisconnectReason::NewConnection))
+}
@@ -0,0 +1,237 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::ws::{Message as WsMessage, WebSocket};
+use futures_util::{
+    SinkExt, StreamExt,
+    stream::{SplitSink, SplitStream},
+};
+use tokio::time::{Instant, interval};
+
+use super::{
+    LoopExit, close_oversized,
+    codec::Codec,
+    delivery::{Delivery, Mailbox},
+    opaque,
+    protocol::{self, FrameRef, Rejection},
+    rate_limit::FloodGuard,
+    send_frames, user,
+};
+use crate::{
+    AppState,
+    domain::{
+        event::{DisconnectReason, ErrorCode},
+        message::ToUserMessage,
+        user::UserId,
+    },
+    metrics::METRICS,
+};
+
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Watch a room: host broadcasts come in, nothing goes out. Spectators are
+/// not members, so the host only sees how many there are.
+pub async fn handle_spectator_ws(
+    socket: WebSocket,
+    state: Arc<AppState>,
+    room_id: String,
+    user_id: UserId,
+) {
+    let codec = Codec::from_protocol(socket.protocol());
+    let mut mailbox = Mailbox {
+        bus_rx: state.message_bus.register_spectator(&room_id, &user_id),
+        delivery: Delivery::new(false, codec),
+    };
+
+    if !state.storage.add_spectator(&room_id, &user_id) {
+        state.message_bus.unregister_spectator(&room_id, &user_id);
+        user::reject_user(socket, &room_id, user_id, DisconnectReason::RoomClosed).await;
+        return;
+    }
+    tracing::info!("Spectator {} watching room {}", user_id.as_str(), room_id);
+
+    // Session first, then the broadcasts recorded before the spectator came
+    let (mut ws_sender, mut ws_receiver) = socket.split();
+    let mut greeting = vec![ToUserMessage::session(user_id.clone(), None, false)];
+    if let Some(history) = state.storage.get_history(&room_id, None) {
+        greeting.push(ToUserMessage::history(user_id.clone(), history));
+    }
+    let opened = match greeting
+        .iter()
+        .map(|msg| mailbox.delivery.frame(msg))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(frames) => send_frames(&mut ws_sender, frames).await,
+        Err(e) => {
+            tracing::error!(
+                "Failed to serialize session for spectator {}: {}",
+                user_id.as_str(),
+                e
+            );
+            false
+        }
+    };
+
+    METRICS.connected_spectators.inc();
+    let exit = if opened {
+        run_spectator_loop(
+            &state,
+            &mut ws_sender,
+            &mut ws_receiver,
+            &mut mailbox,
+            &room_id,
+            &user_id,
+        )
+        .await
+    } else {
+        LoopExit::Dropped
+    };
+    METRICS.connected_spectators.dec();
+
+    // A newer connection of the same spectator owns the channel now
+    if !matches!(exit, LoopExit::Replaced) {
+        state.message_bus.unregister_spectator(&room_id, &user_id);
+    }
+    state.storage.remove_spectator(&room_id, &user_id);
+    tracing::info!(
+        "Spectator {} stopped watching room {}",
+        user_id.as_str(),
+        room_id
+    );
+}
+
+async fn run_spectator_loop(
+    state: &AppState,
+    ws_sender: &mut SplitSink<WebSocket, WsMessage>,
+    ws_receiver: &mut SplitStream<WebSocket>,
+    mailbox: &mut Mailbox<ToUserMessage>,
+    room_id: &str,
+    user_id: &UserId,
+) -> LoopExit {
+    let mut ping_interval = interval(PING_INTERVAL);
+    ping_interval.tick().await; // consume first immediate tick
+    let mut pong_deadline: Option<Instant> = None;
+    let mut frames_received: u64 = 0;
+    let room_type = state
+        .storage
+        .get_room(room_id)
+        .map(|room| room.room_type.as_str().to_string())
+        .unwrap_or_default();
+    let mut guard = FloodGuard::spectator(
+        state.config.rate_limits.for_room_type(&room_type),
+        state.config.rate_limit_strikes,
+    );
+
+    loop {
+        tokio::select! {
+            // Broadcast from the host via the bus -> forward to spectator WS
+            msg = mailbox.bus_rx.recv() => {
+                match msg {
+                    Some(msg) => {
+                        // Opaque frames go out as they came, without a sequence number
+                        let frame = match &msg.data {
+                            Some(data) => Ok(opaque::to_user(data)),
+                            None => mailbox.delivery.frame(&msg),
+                        };
+                        match frame {
+                            Ok(frame) => {
+                                if ws_sender.send(frame).await.is_err() {
+                                    return LoopExit::Dropped;
+                                }
+                            }
+                            Err(e) => {
+                                tracing::error!("Failed to serialize message for spectator {}: {}", user_id.as_str(), e);
+                            }
+                        }
+
+                        if let Some(reason) = msg.disconnect_reason() {
+                            METRICS.record_disconnect("spectator", &reason);
+                            return LoopExit::Closed;
+                        }
+                    }
+                    None if mailbox.bus_rx.overflowed() => {
+                        tracing::warn!("Spectator {} fell behind its queue, disconnecting", user_id.as_str());
+                        METRICS.record_disconnect("spectator", &DisconnectReason::SlowConsumer);
+                        let msg = ToUserMessage::disconnect(user_id.clone(), DisconnectReason::SlowConsumer);
+                        if let Ok(frame) = mailbox.delivery.codec().encode(&msg) {
+                            let _ = ws_sender.send(frame).await;
+                        }
+                        return LoopExit::Closed;
+                    }
+                    None => {
+                        // Channel closed: a newer connection of this spectator registered
+                        return LoopExit::Replaced;
+                    }
+                }
+            }
+
+            // Anything the spectator sends is refused, and flooding with it
+            // costs the connection
+            ws_msg = ws_receiver.next() => {
+                match ws_msg {
+                    Some(Ok(WsMessage::Text(_) | WsMessage::Binary(_))) => {
+                        frames_received += 1;
+                        let (rejection, strike_out) = match guard.admit(&state.rate_limiter, room_id, user_id) {
+                            Ok(()) => (
+                                Rejection::new(ErrorCode::ReadOnly, "spectators cannot send messages", FrameRef::default()),
+                                false,
+                            ),
+                            Err(throttled) => (throttled.rejection, throttled.strike_out),
+                        };
+                        let msg = ToUserMessage::error(user_id.clone(), rejection.payload(frames_received));
+                        if let Ok(frame) = mailbox.delivery.codec().encode(&msg)
+                            && ws_sender.send(frame).await.is_err()
+                        {
+                            return LoopExit::Dropped;
+                        }
+                        if strike_out {
+                            tracing::warn!("Spectator {} kept flooding room {}, disconnecting", user_id.as_str(), room_id);
+                            METRICS.record_disconnect("spectator", &DisconnectReason::RateLimited);
+                            let msg = ToUserMessage::disconnect(user_id.clone(), DisconnectReason::RateLimited);
+                            if let Ok(frame) = mailbox.delivery.codec().encode(&msg) {
+                                let _ = ws_sender.send(frame).await;
+                            }
+                            return LoopExit::Closed;
+                        }
+                    }
+                    Some(Ok(WsMessage::Pong(_))) => {
+                        pong_deadline = None;
+                    }
+                    Some(Ok(WsMessage::Close(_))) => {
+                        METRICS.record_disconnect("spectator", &DisconnectReason::UserClosed);
+                        return LoopExit::Closed;
+                    }
+                    None => {
+                        return LoopExit::Dropped;
+                    }
+                    Some(Err(e)) if protocol::is_too_large(&e) => {
+                        tracing::warn!("Spectator {} sent a frame over the size limit of room {}: {}", user_id.as_str(), room_id, e);
+                        let msg = ToUserMessage::disconnect(user_id.clone(), DisconnectReason::MessageTooLarge);
+                        close_oversized(ws_sender, mailbox.delivery.codec().encode(&msg), "spectator", "size").await;
+                        return LoopExit::Closed;
+                    }
+                    Some(Err(e)) => {
+                        tracing::error!("WebSocket error for spectator {}: {}", user_id.as_str(), e);
+                        return LoopExit::Dropped;
+                    }
+                    _ => {}
+                }
+            }
+
+            // Ping tick
+            _ = ping_interval.tick() => {
+                if let Some(deadline) = pong_deadline
+                    && Instant::now() > deadline {
+                        tracing::warn!("Spectator {} pong timeout, disconnecting", user_id.as_str());
+                        METRICS.record_pong_timeout("spectator");
+                        return LoopExit::Dropped;
+                    }
+                if ws_sender.send(WsMessage::Ping(vec![].into())).await.is_err() {
+                    return LoopExit::Dropped;
+                }
+                pong_deadline = Some(Instant::now() + PONG_TIMEOUT);
+            }
+        }
+    }
+}
@@ -1,66 +1,313 @@
 use std::sync::Arc;
 use std::time::Duration;
 
-use axum::extract::ws::{Message as WsMessage, WebSocket};
-use futures_util::{SinkExt, StreamExt};
+use axum::extract::ws::{CloseFrame, Message as WsMessage, WebSocket, close_code};
+use futures_util::{
+    SinkExt, StreamExt,
+    stream::{SplitSink, SplitStream},
+};
 use tokio::time::{Instant, interval};
 
+use super::{
+    LoopExit, close_oversized,
+    codec::Codec,
+    connections::Peer,
+    delivery::{Delivery, Mailbox},
+    host, opaque,
+    protocol::{self, FrameRef, Rejection},
+    rate_limit::FloodGuard,
+    send_frames, send_or_return,
+    session::{ParkedUser, SessionRegistry},
+};
 use crate::{
     AppState,
     domain::{
-        event::ToUserEvent,
-        message::{ToHostMessage, UserWebSocketMessage},
+        event::{DisconnectReason, ErrorCode, ToUserEvent},
+        message::{ToHostMessage, ToUserMessage, UserWebSocketMessage},
         user::UserId,
     },
+    message_bus::Receiver,
+    metrics::METRICS,
+    storage::{JoinOutcome, LeaveOutcome},
 };
 
 const PING_INTERVAL: Duration = Duration::from_secs(30);
 const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+/// Snapshots sent while the shared state keeps changing under them
+const STATE_SNAPSHOT_ATTEMPTS: usize = 3;
 
 pub async fn handle_user_ws(
     socket: WebSocket,
     state: Arc<AppState>,
     room_id: String,
     user_id: UserId,
+    peer: Peer,
+    resume_token: Option<String>,
+    ack_mode: bool,
 ) {
-    // Register user in room and message bus
-    state.storage.add_user_to_room(&room_id, user_id.clone());
-    let mut bus_rx = state.message_bus.register_user(&user_id, &room_id);
+    let codec = Codec::from_protocol(socket.protocol());
+    let parked = resume_token
+        .as_deref()
+        .and_then(|token| state.sessions.resume_user(token, &user_id, &room_id));
+    let resumed = parked.is_some();
 
-    // Notify host of user join
-    state
-        .message_bus
-        .send_to_host(&room_id, ToHostMessage::join_room(user_id.clone()));
+    let (mut mailbox, admitted) = match parked {
+        Some(mut parked) => {
+            tracing::info!(
+                "User {} resumed session in room {}",
+                user_id.as_str(),
+                room_id
+            );
+            let admitted = state.storage.is_user_in_room(&room_id, &user_id);
+            parked.mailbox.delivery.reconnect(ack_mode, codec);
+            (parked.mailbox, admitted)
+        }
+        None => {
+            // Register user in room and message bus
+            let outcome = state
+                .storage
+                .add_user_to_room(&room_id, user_id.clone(), peer.can_host);
+            let rejection = match outcome {
+                JoinOutcome::Full => Some(DisconnectReason::RoomFull),
+                JoinOutcome::RoomNotFound => Some(DisconnectReason::RoomClosed),
+                _ => None,
+            };
+            if let Some(reason) = rejection {
+                reject_user(socket, &room_id, user_id, reason).await;
+                return;
+            }
+            // Older parked sessions lose their channel to this connection
+            state.sessions.discard_user(&user_id, &room_id);
+            let mailbox = Mailbox {
+                bus_rx: state.message_bus.register_user(&user_id, &room_id),
+                delivery: Delivery::new(ack_mode, codec),
+            };
+
+            if let JoinOutcome::Waitlisted(position) = outcome {
+                tracing::info!(
+                    "User {} waitlisted in room {} at position {}",
+                    user_id.as_str(),
+                    room_id,
+                    position
+                );
+                state
+                    .message_bus
+                    .send_to_host(
+                        &room_id,
+                        ToHostMessage::waitlisted(user_id.clone(), position),
+                    )
+                    .await;
+                state
+                    .message_bus
+                    .send_to_user(
+                        &user_id,
+                        &room_id,
+                        ToUserMessage::waitlisted(user_id.clone(), position),
+                    )
+                    .await;
+                (mailbox, false)
+            } else {
+                // Notify host of user join
+                state
+                    .message_bus
+                    .send_to_host(&room_id, ToHostMessage::join_room(user_id.clone()))
+                    .await;
+                send_state_snapshot(&state, &room_id, &user_id).await;
+                send_history(&state, &room_id, &user_id).await;
+                (mailbox, true)
+            }
+        }
+    };
 
     let (mut ws_sender, mut ws_receiver) = socket.split();
+
+    let resume_enabled = !state.config.user_resume_grace.is_zero();
+    let token = SessionRegistry::new_token();
+    let session = ToUserMessage::session(
+        user_id.clone(),
+        resume_enabled.then_some(token.as_str()),
+        resumed,
+    );
+    // A resumed session gets what it did not acknowledge right after Session
+    let opened = match codec.encode(&session) {
+        Ok(frame) => {
+            let mut frames = vec![frame];
+            frames.extend(mailbox.delivery.redelivery());
+            send_frames(&mut ws_sender, frames).await
+        }
+        Err(e) => {
+            tracing::error!(
+                "Failed to serialize session for user {}: {}",
+                user_id.as_str(),
+                e
+            );
+            false
+        }
+    };
+    if opened {
+        mailbox.delivery.redelivered();
+    }
+    METRICS.connected_users.inc();
+    let connection = state.connections.open_user(&room_id, &user_id, peer, codec);
+    let exit = if opened {
+        run_user_loop(
+            &mut ws_sender,
+            &mut ws_receiver,
+            &mut mailbox,
+            &state,
+            &room_id,
+            &user_id,
+            admitted,
+        )
+        .await
+    } else {
+        LoopExit::Dropped
+    };
+    state.connections.close_user(&room_id, &user_id, connection);
+    METRICS.connected_users.dec();
+
+    match exit {
+        LoopExit::Dropped if resume_enabled => {
+            park_user_session(
+                &state,
+                token,
+                ParkedUser {
+                    user_id,
+                    room_id,
+                    mailbox,
+                },
+            );
+        }
+        LoopExit::Replaced => {
+            tracing::info!(
+                "User {} connection to room {} replaced by a newer one",
+                user_id.as_str(),
+                room_id
+            );
+        }
+        LoopExit::Promoted => {
+            tracing::info!(
+                "User {} takes over room {} as its host",
+                user_id.as_str(),
+                room_id
+            );
+            // The new host is no longer a member of the room
+            cleanup_user_disconnect(&state, &room_id, &user_id).await;
+            host::take_over(
+                ws_sender,
+                ws_receiver,
+                mailbox.delivery,
+                state,
+                room_id,
+                user_id,
+                peer,
+            )
+            .await;
+        }
+        _ => {
+            // Cleanup
+            cleanup_user_disconnect(&state, &room_id, &user_id).await;
+        }
+    }
+}
+
+async fn run_user_loop(
+    ws_sender: &mut SplitSink<WebSocket, WsMessage>,
+    ws_receiver: &mut SplitStream<WebSocket>,
+    mailbox: &mut Mailbox<ToUserMessage>,
+    state: &AppState,
+    room_id: &str,
+    user_id: &UserId,
+    mut admitted: bool,
+) -> LoopExit {
     let mut ping_interval = interval(PING_INTERVAL);
     ping_interval.tick().await; // consume first immediate tick
     let mut pong_deadline: Option<Instant> = None;
+    let mut frames_received: u64 = 0;
+    let room_type = state
+        .storage
+        .get_room(room_id)
+        .map(|room| room.room_type.as_str().to_string())
+        .unwrap_or_default();
+    let mut guard = FloodGuard::new(
+        state.config.rate_limits.for_room_type(&room_type),
+        state.config.rate_limit_strikes,
+        &room_type,
+        false,
+    );
+    let max_depth = state
+        .config
+        .message_limits
+        .for_room_type(&room_type)
+        .max_depth;
 
     loop {
         tokio::select! {
             // Message from host via bus -> forward to user WS
-            msg = bus_rx.recv() => {
+            msg = mailbox.bus_rx.recv() => {
                 match msg {
+                    Some(_) if mailbox.delivery.is_behind() => {
+                        tracing::warn!("User {} stopped acknowledging messages, disconnecting", user_id.as_str());
+                        METRICS.record_disconnect("user", &DisconnectReason::SlowConsumer);
+                        let msg = ToUserMessage::disconnect(user_id.clone(), DisconnectReason::SlowConsumer);
+                        if let Ok(frame) = mailbox.delivery.codec().encode(&msg) {
+                            let _ = ws_sender.send(frame).await;
+                        }
+                        return LoopExit::Closed;
+                    }
                     Some(msg) => {
-                        let is_disconnect = matches!(msg.event, ToUserEvent::Disconnect);
+                        let reason = msg.disconnect_reason();
+                        if matches!(msg.event, ToUserEvent::Promoted) {
+                            admitted = true;
+                        }
 
-                        match serde_json::to_string(&msg) {
-                            Ok(json) => {
-                                let _ = ws_sender.send(WsMessage::Text(json.into())).await;
+                        // Opaque frames go out as they came, without a sequence number
+                        let frame = match &msg.data {
+                            Some(data) => Ok(opaque::to_user(data)),
+                            None => mailbox.delivery.frame(&msg),
+                        };
+                        match frame {
+                            Ok(frame) => {
+                                if let Err(frame) = send_or_return(ws_sender, frame).await {
+                                    mailbox.delivery.keep_unsent(frame, msg.data.is_none());
+                                    return LoopExit::Dropped;
+                                }
                             }
                             Err(e) => {
                                 tracing::error!("Failed to serialize message for user {}: {}", user_id.as_str(), e);
                             }
                         }
 
-                        if is_disconnect {
-                            break;
+                        if let Some(reason) = &reason {
+                            METRICS.record_disconnect("user", reason);
+                        }
+                        // A member with the Host role takes over on this socket
+                        if msg.new_host().is_some_and(|host_id| host_id == *user_id)
+                            && state
+                                .connections
+                                .user(room_id, user_id)
+                                .is_some_and(|connection| connection.can_host)
+                        {
+                            return LoopExit::Promoted;
+                        }
+                        match reason {
+                            Some(DisconnectReason::NewConnection) => return LoopExit::Replaced,
+                            Some(_) => return LoopExit::Closed,
+                            None => {}
                         }
                     }
+                    None if mailbox.bus_rx.overflowed() => {
+                        tracing::warn!("User {} fell behind its queue, disconnecting", user_id.as_str());
+                        METRICS.record_disconnect("user", &DisconnectReason::SlowConsumer);
+                        let msg = ToUserMessage::disconnect(user_id.clone(), DisconnectReason::SlowConsumer);
+                        if let Ok(frame) = mailbox.delivery.codec().encode(&msg) {
+                            let _ = ws_sender.send(frame).await;
+                        }
+                        return LoopExit::Closed;
+                    }
                     None => {
                         // Channel closed (host disconnected / room closed)
-                        break;
+                        return LoopExit::Closed;
                     }
                 }
             }
@@ -68,18 +315,60 @@ pub async fn handle_user_ws(
             // Message from user WS -> route to host
             ws_msg = ws_receiver.next() => {
                 match ws_msg {
-                    Some(Ok(WsMessage::Text(text))) => {
-                        handle_user_message(&state, &room_id, &user_id, &text);
+                    Some(Ok(frame @ (WsMessage::Text(_) | WsMessage::Binary(_)))) => {
+                        frames_received += 1;
+                        let mut strike_out = false;
+                        let handled = match guard.admit(&state.rate_limiter, room_id, user_id) {
+                            Ok(()) => handle_user_frame(state, room_id, user_id, &mut mailbox.delivery, admitted, max_depth, &frame).await,
+                            Err(throttled) => {
+                                strike_out = throttled.strike_out;
+                                Err(throttled.rejection)
+                            }
+                        };
+                        if let Err(rejection) = handled {
+                            tracing::warn!("Rejected frame {} from user {}: {}", frames_received, user_id.as_str(), rejection);
+                            let msg = ToUserMessage::error(user_id.clone(), rejection.payload(frames_received));
+                            if let Ok(frame) = mailbox.delivery.codec().encode(&msg)
+                                && ws_sender.send(frame).await.is_err()
+                            {
+                                return LoopExit::Dropped;
+                            }
+                            if rejection.code() == ErrorCode::PayloadTooLarge {
+                                tracing::warn!("User {} went over the message limits of room {}, disconnecting", user_id.as_str(), room_id);
+                                let msg = ToUserMessage::disconnect(user_id.clone(), DisconnectReason::MessageTooLarge);
+                                close_oversized(ws_sender, mailbox.delivery.codec().encode(&msg), "user", "depth").await;
+                                return LoopExit::Closed;
+                            }
+                            if strike_out {
+                                tracing::warn!("User {} kept flooding room {}, disconnecting", user_id.as_str(), room_id);
+                                METRICS.record_disconnect("user", &DisconnectReason::RateLimited);
+                                let msg = ToUserMessage::disconnect(user_id.clone(), DisconnectReason::RateLimited);
+                                if let Ok(frame) = mailbox.delivery.codec().encode(&msg) {
+                                    let _ = ws_sender.send(frame).await;
+                                }
+                                return LoopExit::Closed;
+                            }
+                        }
                     }
                     Some(Ok(WsMessage::Pong(_))) => {
                         pong_deadline = None;
                     }
-                    Some(Ok(WsMessage::Close(_))) | None => {
-                        break;
+                    Some(Ok(WsMessage::Close(_))) => {
+                        METRICS.record_disconnect("user", &DisconnectReason::UserClosed);
+                        return LoopExit::Closed;
+                    }
+                    None => {
+                        return LoopExit::Dropped;
+                    }
+                    Some(Err(e)) if protocol::is_too_large(&e) => {
+                        tracing::warn!("User {} sent a frame over the size limit of room {}: {}", user_id.as_str(), room_id, e);
+                        let msg = ToUserMessage::disconnect(user_id.clone(), DisconnectReason::MessageTooLarge);
+                        close_oversized(ws_sender, mailbox.delivery.codec().encode(&msg), "user", "size").await;
+                        return LoopExit::Closed;
                     }
                     Some(Err(e)) => {
                         tracing::error!("WebSocket error for user {}: {}", user_id.as_str(), e);
-                        break;
+                        return LoopExit::Dropped;
                     }
                     _ => {}
                 }
@@ -90,42 +379,210 @@ pub async fn handle_user_ws(
                 if let Some(deadline) = pong_deadline
                     && Instant::now() > deadline {
                         tracing::warn!("User {} pong timeout, disconnecting", user_id.as_str());
-                        break;
+                        METRICS.record_pong_timeout("user");
+                        return LoopExit::Dropped;
                     }
                 if ws_sender.send(WsMessage::Ping(vec![].into())).await.is_err() {
-                    break;
+                    return LoopExit::Dropped;
                 }
                 pong_deadline = Some(Instant::now() + PONG_TIMEOUT);
             }
         }
     }
+}
+
+/// Act on a data frame from the user, or say why it was refused
+async fn handle_user_frame(
+    state: &AppState,
+    room_id: &str,
+    user_id: &UserId,
+    delivery: &mut Delivery,
+    admitted: bool,
+    max_depth: usize,
+    frame: &WsMessage,
+) -> Result<(), Rejection> {
+    if let WsMessage::Binary(bytes) = frame
+        && opaque::is_opaque(bytes)
+    {
+        check_admitted(admitted, FrameRef::default())?;
+        let msg = ToHostMessage::binary(user_id.clone(), opaque::user_payload(bytes));
+        state.message_bus.send_to_host(room_id, msg).await;
+        return Ok(());
+    }
 
-    // Cleanup
-    cleanup_user_disconnect(&state, &room_id, &user_id).await;
+    let (msg, frame_ref) = protocol::decode(delivery.codec(), frame, max_depth)?;
+    match msg {
+        // Waitlisted users receive messages too, so they may acknowledge them
+        UserWebSocketMessage::Ack { seq } => delivery.ack(seq),
+        UserWebSocketMessage::Message { message } => {
+            check_admitted(admitted, frame_ref)?;
+            state
+                .message_bus
+                .send_to_host(room_id, ToHostMessage::message(user_id.clone(), message))
+                .await;
+        }
+        UserWebSocketMessage::GetState => {
+            check_admitted(admitted, frame_ref)?;
+            send_state_snapshot(state, room_id, user_id).await;
+        }
+        UserWebSocketMessage::Unknown => {
+            return Err(Rejection::new(
+                ErrorCode::UnknownEvent,
+                "unknown event",
+                frame_ref,
+            ));
+        }
+    }
+    Ok(())
 }
 
-fn handle_user_message(state: &AppState, room_id: &str, user_id: &UserId, text: &str) {
-    let msg: UserWebSocketMessage = match serde_json::from_str(text) {
-        Ok(msg) => msg,
-        Err(e) => {
-            tracing::warn!("Invalid message from user {}: {}", user_id.as_str(), e);
-            return;
+/// Waitlisted users are not in the room yet and cannot message the host
+fn check_admitted(admitted: bool, frame_ref: FrameRef) -> Result<(), Rejection> {
+    if admitted {
+        return Ok(());
+    }
+    Err(Rejection::new(
+        ErrorCode::UserNotInRoom,
+        "waitlisted users cannot send messages",
+        frame_ref,
+    ))
+}
+
+/// Keep the user in the room while the client reconnects. Messages keep
+/// buffering in the bus channel; if the grace window passes without a
+/// resume, the user leaves the room as usual.
+fn park_user_session(state: &Arc<AppState>, token: String, parked: ParkedUser) {
+    tracing::info!(
+        "User {} dropped from room {}, waiting {:?} for resume",
+        parked.user_id.as_str(),
+        parked.room_id,
+        state.config.user_resume_grace
+    );
+
+    state.sessions.park_user(token.clone(), parked);
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(state.config.user_resume_grace).await;
+
+        if let Some(mut parked) = state.sessions.expire_user(&token) {
+            if was_replaced(&mut parked.mailbox.bus_rx) {
+                return;
+            }
+            cleanup_user_disconnect(&state, &parked.room_id, &parked.user_id).await;
         }
-    };
+    });
+}
 
-    match msg.event.as_str() {
-        "MESSAGE" => {
-            state.message_bus.send_to_host(
-                room_id,
-                ToHostMessage::message(user_id.clone(), msg.message),
-            );
+/// Whether a newer connection of the same user has taken over while parked
+fn was_replaced(bus_rx: &mut Receiver<ToUserMessage>) -> bool {
+    while let Some(msg) = bus_rx.try_recv() {
+        if msg.disconnect_reason() == Some(DisconnectReason::NewConnection) {
+            return true;
         }
-        other => {
-            tracing::warn!("Unknown event '{}' from user {}", other, user_id.as_str());
+    }
+    false
+}
+
+/// Refuse a joiner with a Disconnect event and a close frame carrying the reason
+pub(super) async fn reject_user(
+    mut socket: WebSocket,
+    room_id: &str,
+    user_id: UserId,
+    reason: DisconnectReason,
+) {
+    tracing::info!(
+        "User {} rejected from room {}: {:?}",
+        user_id.as_str(),
+        room_id,
+        reason
+    );
+
+    METRICS.record_disconnect("user", &reason);
+    // Only a full room is worth retrying later
+    let code = match reason {
+        DisconnectReason::RoomFull => close_code::AGAIN,
+        _ => close_code::NORMAL,
+    };
+    let close_reason = format!("{reason:?}");
+    let codec = Codec::from_protocol(socket.protocol());
+    if let Ok(frame) = codec.encode(&ToUserMessage::disconnect(user_id, reason)) {
+        let _ = socket.send(frame).await;
+    }
+    let _ = socket
+        .send(WsMessage::Close(Some(CloseFrame {
+            code,
+            reason: close_reason.into(),
+        })))
+        .await;
+}
+
+/// Queue a snapshot of the shared state behind whatever the user was
+/// already sent. A diff queued ahead of it may belong to a later version
+/// than the snapshot, so the snapshot is sent again until it is current.
+async fn send_state_snapshot(state: &AppState, room_id: &str, user_id: &UserId) {
+    let mut sent = None;
+    for _ in 0..STATE_SNAPSHOT_ATTEMPTS {
+        let Some(room) = state.storage.get_room(room_id) else {
+            return;
+        };
+        if sent == Some(room.state_version) {
+            return;
         }
+        sent = Some(room.state_version);
+        state
+            .message_bus
+            .send_to_user(
+                user_id,
+                room_id,
+                ToUserMessage::state(user_id.clone(), room.state_version, room.shared_state),
+            )
+            .await;
     }
 }
 
+/// Replay the broadcasts recorded before the user was admitted. Later
+/// ones reach the user as members anyway.
+async fn send_history(state: &AppState, room_id: &str, user_id: &UserId) {
+    let Some(member) = state.storage.get_room_member(room_id, user_id) else {
+        return;
+    };
+    let Some(history) = state
+        .storage
+        .get_history(room_id, Some(member.history_seen))
+    else {
+        return;
+    };
+    state
+        .message_bus
+        .send_to_user(
+            user_id,
+            room_id,
+            ToUserMessage::history(user_id.clone(), history),
+        )
+        .await;
+}
+
+/// Tell the host and a user admitted from the waitlist, then bring the
+/// user up to date like any joiner
+pub(super) async fn announce_promotion(state: &AppState, room_id: &str, promoted: &UserId) {
+    tracing::info!(
+        "User {} promoted from waitlist in room {}",
+        promoted.as_str(),
+        room_id
+    );
+    state
+        .message_bus
+        .send_to_host(room_id, ToHostMessage::promoted(promoted.clone()))
+        .await;
+    state
+        .message_bus
+        .send_to_user(promoted, room_id, ToUserMessage::promoted(promoted.clone()))
+        .await;
+    send_state_snapshot(state, room_id, promoted).await;
+    send_history(state, room_id, promoted).await;
+}
+
 async fn cleanup_user_disconnect(state: &AppState, room_id: &str, user_id: &UserId) {
     tracing::info!(
         "User {} disconnected from room {}",
@@ -133,14 +590,51 @@ async fn cleanup_user_disconnect(state: &AppState, room_id: &str, user_id: &User
         room_id
     );
 
-    // Remove user from room
-    state.storage.remove_user_from_room(room_id, user_id);
+    leave_room(state, room_id, user_id).await;
 
     // Unregister user channel
     state.message_bus.unregister_user(user_id, room_id);
+}
 
-    // Notify host that user left
+/// Take a member or waitlisted user out of the room and disconnect them
+/// with `reason`. The membership goes right away, so the room is freed even
+/// if the user is parked or their socket never gets the message. Returns
+/// false if the user was neither a member nor waitlisted.
+pub async fn kick_user(
+    state: &AppState,
+    room_id: &str,
+    user_id: &UserId,
+    reason: DisconnectReason,
+) -> bool {
+    let outcome = leave_room(state, room_id, user_id).await;
     state
         .message_bus
-        .send_to_host(room_id, ToHostMessage::leave_room(user_id.clone()));
+        .send_to_user(
+            user_id,
+            room_id,
+            ToUserMessage::disconnect(user_id.clone(), reason),
+        )
+        .await;
+    outcome != LeaveOutcome::NotInRoom
+}
+
+/// Remove a user from the room, admitting the next waitlisted user, and
+/// tell the host if the user had joined
+async fn leave_room(state: &AppState, room_id: &str, user_id: &UserId) -> LeaveOutcome {
+    let outcome = state.storage.remove_user_from_room(room_id, user_id);
+    if let LeaveOutcome::Left {
+        promoted: Some(promoted),
+    } = &outcome
+    {
+        announce_promotion(state, room_id, promoted).await;
+    }
+
+    // The host only saw waitlisted users as Waitlisted, never as joined
+    if matches!(outcome, LeaveOutcome::Left { .. }) {
+        state
+            .message_bus
+            .send_to_host(room_id, ToHostMessage::leave_room(user_id.clone()))
+            .await;
+    }
+    outcome
 }